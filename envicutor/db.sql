@@ -4,3 +4,38 @@ CREATE TABLE IF NOT EXISTS runtime (
     source_file_name VARCHAR(256) NOT NULL,
     created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
 );
+CREATE TABLE IF NOT EXISTS installation (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name VARCHAR(256) NOT NULL,
+    status VARCHAR(16) NOT NULL,
+    runtime_id INTEGER,
+    error TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMP
+);
+CREATE TABLE IF NOT EXISTS runtime_version (
+    runtime_id INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    source_file_name VARCHAR(256) NOT NULL,
+    is_active BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (runtime_id, version)
+);
+CREATE TABLE IF NOT EXISTS runtime_alias (
+    alias VARCHAR(256) PRIMARY KEY,
+    runtime_id INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS runtime_tag (
+    runtime_id INTEGER NOT NULL,
+    tag VARCHAR(256) NOT NULL,
+    PRIMARY KEY (runtime_id, tag)
+);
+CREATE TABLE IF NOT EXISTS nix_update (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    operation VARCHAR(16) NOT NULL,
+    nixpkgs VARCHAR(256),
+    generation_before INTEGER,
+    generation_after INTEGER,
+    succeeded BOOLEAN NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,124 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::Path,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task};
+
+use crate::{
+    api::errors::ApiError,
+    globals::DB_PATH,
+    types::{Aliases, Metadata},
+};
+
+#[derive(Serialize)]
+pub struct Alias {
+    alias: String,
+    runtime_id: u32,
+}
+
+#[derive(Deserialize)]
+pub struct SetAliasRequest {
+    runtime_id: u32,
+}
+
+pub async fn list_aliases(aliases_cache: Arc<RwLock<Aliases>>) -> impl IntoResponse {
+    let aliases_guard = aliases_cache.read().await;
+    let mut aliases: Vec<Alias> = aliases_guard
+        .iter()
+        .map(|(alias, runtime_id)| Alias {
+            alias: alias.clone(),
+            runtime_id: *runtime_id,
+        })
+        .collect();
+    aliases.sort_by(|a, b| a.alias.cmp(&b.alias));
+    Json(aliases)
+}
+
+/// Creates the alias or atomically points it to another runtime
+pub async fn set_alias(
+    Path(alias): Path<String>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    Json(req): Json<SetAliasRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    let mut aliases_guard = aliases_cache.write().await;
+    let metadata_guard = metadata_cache.read().await;
+    // Names are reserved while their runtimes are installed or renamed to them
+    let is_reserved = installations
+        .lock()
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to lock installations in progress: {e}")))?
+        .contains(&alias);
+    let bad_request_message = if alias.is_empty() {
+        Some("Alias can't be empty".to_string())
+    } else if metadata_guard.values().any(|runtime| runtime.name == alias) {
+        Some("A runtime with this name already exists".to_string())
+    } else if is_reserved {
+        Some("A runtime with this name is being installed".to_string())
+    } else if !metadata_guard.contains_key(&req.runtime_id) {
+        Some(format!(
+            "Runtime with id: {} does not exist",
+            req.runtime_id
+        ))
+    } else {
+        None
+    };
+    drop(metadata_guard);
+    if let Some(message) = bad_request_message {
+        return Err(ApiError::bad_request(message).into());
+    }
+
+    let runtime_id = req.runtime_id;
+    let alias_clone = alias.clone();
+    task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO runtime_alias (alias, runtime_id) VALUES (?, ?)",
+                (&alias_clone, runtime_id),
+            )
+            .map_err(|e| anyhow!("Failed to set alias {alias_clone}: {e}"))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res)
+    .map_err(ApiError::Internal)?;
+    aliases_guard.insert(alias, runtime_id);
+    Ok(().into_response())
+}
+
+pub async fn delete_alias(
+    Path(alias): Path<String>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let mut aliases_guard = aliases_cache.write().await;
+    if !aliases_guard.contains_key(&alias) {
+        return Err(ApiError::not_found("Could not find the specified alias").into());
+    }
+
+    let alias_clone = alias.clone();
+    task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        connection
+            .execute("DELETE FROM runtime_alias WHERE alias = ?", [&alias_clone])
+            .map_err(|e| anyhow!("Failed to delete alias {alias_clone}: {e}"))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res)
+    .map_err(ApiError::Internal)?;
+    aliases_guard.remove(&alias);
+    Ok(().into_response())
+}
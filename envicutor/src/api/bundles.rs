@@ -0,0 +1,185 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use crate::{
+    api::{
+        editing::get_current_definition,
+        errors::ApiError,
+        installation::{
+            prepare_request, start_installation, AddRuntimeRequest, EnvironmentSource,
+            NameReservation,
+        },
+        tags::prepare_tags,
+    },
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    installation_jobs::InstallationJobs,
+    limits::SystemLimits,
+    runtime_usage::RuntimeUsage,
+    runtime_versions::get_version_dir,
+    types::{Aliases, Metadata, Runtime, WholeSeconds},
+};
+
+/// Increased on every incompatible change to the bundle format
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+/// Everything needed to install runtimes on another instance
+#[derive(Serialize, Deserialize)]
+pub struct Bundle {
+    pub format_version: u32,
+    pub runtimes: Vec<BundledRuntime>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BundledRuntime {
+    #[serde(flatten)]
+    pub definition: AddRuntimeRequest,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportedRuntime {
+    name: String,
+    installation_id: u32,
+}
+
+pub async fn bundle_runtime(id: u32, runtime: &Runtime) -> Result<BundledRuntime, ApiError> {
+    let definition = get_current_definition(&get_version_dir(id, runtime.version), runtime).await?;
+    Ok(BundledRuntime {
+        definition,
+        tags: runtime.tags.clone(),
+    })
+}
+
+pub async fn export_runtime(
+    Path(id): Path<u32>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+) -> Result<Response<Body>, Response<Body>> {
+    // Held so the files of the runtime can't be deleted while they are being read
+    let _lease = runtime_usage
+        .acquire(id)
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire runtime lease: {e}")))?
+        .ok_or_else(|| ApiError::conflict(format!("Runtime with id: {id} is being deleted")))?;
+    let runtime = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Could not find the specified runtime"))?;
+    Ok(Json(Bundle {
+        format_version: BUNDLE_FORMAT_VERSION,
+        runtimes: vec![bundle_runtime(id, &runtime).await?],
+    })
+    .into_response())
+}
+
+/// Exports all the runtimes, the ones that are being deleted are skipped
+pub async fn export_runtimes(
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+) -> Result<Response<Body>, Response<Body>> {
+    let mut runtimes: Vec<(u32, Runtime)> = metadata_cache
+        .read()
+        .await
+        .iter()
+        .map(|(id, runtime)| (*id, runtime.clone()))
+        .collect();
+    runtimes.sort_by_key(|(id, _)| *id);
+
+    let mut bundled_runtimes = Vec::new();
+    for (id, runtime) in runtimes {
+        let Some(_lease) = runtime_usage
+            .acquire(id)
+            .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire runtime lease: {e}")))?
+        else {
+            continue;
+        };
+        // It might have been deleted before the lease was acquired
+        if !metadata_cache.read().await.contains_key(&id) {
+            continue;
+        }
+        bundled_runtimes.push(bundle_runtime(id, &runtime).await?);
+    }
+    Ok(Json(Bundle {
+        format_version: BUNDLE_FORMAT_VERSION,
+        runtimes: bundled_runtimes,
+    })
+    .into_response())
+}
+
+/// Installs every runtime of the bundle in the background, nothing is installed
+/// unless all of them are valid and their names are available
+#[allow(clippy::too_many_arguments)]
+pub async fn import_runtimes(
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_jobs: Arc<InstallationJobs>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    Json(bundle): Json<Bundle>,
+) -> Result<Response<Body>, Response<Body>> {
+    if bundle.format_version != BUNDLE_FORMAT_VERSION {
+        return Err(ApiError::bad_request(format!(
+            "Unsupported bundle format version: {}, expected: {BUNDLE_FORMAT_VERSION}",
+            bundle.format_version
+        ))
+        .into());
+    }
+
+    let mut reserved_runtimes = Vec::new();
+    for mut runtime in bundle.runtimes {
+        prepare_request(&mut runtime.definition, &system_limits).await?;
+        prepare_tags(&mut runtime.tags)?;
+        let name_reservation = NameReservation::new(
+            &runtime.definition.name,
+            &installations,
+            &metadata_cache,
+            &aliases_cache,
+        )
+        .await?;
+        reserved_runtimes.push((name_reservation, runtime));
+    }
+
+    let mut imported_runtimes = Vec::new();
+    for (name_reservation, runtime) in reserved_runtimes {
+        let name = runtime.definition.name.clone();
+        let installation_id = start_installation(
+            installation_timeout,
+            box_id_allocator.clone(),
+            cleaner.clone(),
+            metadata_cache.clone(),
+            installation_jobs.clone(),
+            installation_lock.clone(),
+            system_limits.clone(),
+            name_reservation,
+            runtime.definition,
+            runtime.tags,
+            EnvironmentSource::Build,
+        )
+        .await?;
+        imported_runtimes.push(ImportedRuntime {
+            name,
+            installation_id,
+        });
+    }
+    Ok((StatusCode::ACCEPTED, Json(imported_runtimes)).into_response())
+}
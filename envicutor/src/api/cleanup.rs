@@ -0,0 +1,9 @@
+use std::sync::Arc;
+
+use axum::{response::IntoResponse, Json};
+
+use crate::cleanup::Cleaner;
+
+pub async fn get_cleanup_metrics(cleaner: Arc<Cleaner>) -> impl IntoResponse {
+    Json(cleaner.metrics())
+}
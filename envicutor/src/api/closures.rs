@@ -0,0 +1,335 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error};
+use axum::{
+    body::Body,
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::{stream, StreamExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::Command,
+    sync::RwLock,
+};
+
+use crate::{
+    api::{
+        bundles::{bundle_runtime, Bundle, BUNDLE_FORMAT_VERSION},
+        errors::ApiError,
+        installation::{
+            prepare_request, start_installation, EnvironmentSource, InstallationJobResponse,
+            NameReservation,
+        },
+        tags::prepare_tags,
+    },
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    globals::STAGING_DIR,
+    installation_jobs::{InstallationJob, InstallationJobs},
+    limits::SystemLimits,
+    nix,
+    runtime_env::{self, Env, ENV_FILE, LEGACY_ENV_FILE},
+    runtime_usage::RuntimeUsage,
+    runtime_versions::get_version_dir,
+    temp_dir::TempDir,
+    types::{Aliases, Metadata, WholeSeconds},
+};
+
+const ARCHIVE_NAME: &str = "archive.tar";
+const ARCHIVE_CHUNK_SIZE: usize = 64 * 1024;
+const BUNDLE_FILE: &str = "bundle.json";
+const CLOSURE_FILE: &str = "closure";
+const LOCK_FILE: &str = "lock.json";
+
+fn bad_archive(message: String) -> ApiError {
+    ApiError::bad_request(format!("Invalid closure archive: {message}"))
+}
+
+async fn run_tar(args: &[&str]) -> Result<String, Error> {
+    let res = Command::new("/bin/tar")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run tar: {e}"))?;
+    if !res.status.success() {
+        return Err(anyhow!(
+            "tar failed with\nstderr: {}",
+            String::from_utf8_lossy(&res.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&res.stdout).to_string())
+}
+
+async fn create_temp_dir(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+) -> Result<TempDir, Error> {
+    let box_id = box_id_allocator.allocate()?;
+    TempDir::new(
+        format!("{STAGING_DIR}/{}", box_id.get()),
+        Some(box_id),
+        cleaner,
+    )
+    .await
+}
+
+/// Writes the bundle, the environment, the lock and the closure of a runtime version to a tar archive
+async fn write_archive(
+    version_dir: &str,
+    archive_dir: &str,
+    bundle: &Bundle,
+    export_timeout: WholeSeconds,
+) -> Result<(), Error> {
+    let bundle_path = format!("{archive_dir}/{BUNDLE_FILE}");
+    let bundle =
+        serde_json::to_string(bundle).map_err(|e| anyhow!("Failed to serialize bundle: {e}"))?;
+    fs::write(&bundle_path, bundle)
+        .await
+        .map_err(|e| anyhow!("Failed to write {bundle_path}\nError: {e}"))?;
+
+    let env = runtime_env::read_env(version_dir).await?;
+    runtime_env::write_env(archive_dir, &env).await?;
+    nix::copy_lock(version_dir, archive_dir).await?;
+    nix::export_closure(
+        &runtime_env::find_store_paths(&env),
+        &format!("{archive_dir}/{CLOSURE_FILE}"),
+        export_timeout,
+    )
+    .await?;
+
+    let mut files = vec![BUNDLE_FILE, ENV_FILE, CLOSURE_FILE];
+    if fs::try_exists(format!("{archive_dir}/{LOCK_FILE}"))
+        .await
+        .map_err(|e| anyhow!("Failed to check if the lock exists: {e}"))?
+    {
+        files.push(LOCK_FILE);
+    }
+    let archive_path = format!("{archive_dir}/{ARCHIVE_NAME}");
+    let mut args = vec!["-cf", &archive_path, "-C", archive_dir];
+    args.extend(files);
+    run_tar(&args).await?;
+    Ok(())
+}
+
+/// Packages a runtime with its Nix closure so it can be installed on a machine without network access
+pub async fn export_runtime_closure(
+    Path(id): Path<u32>,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+) -> Result<Response<Body>, Response<Body>> {
+    // Held so the runtime and its store paths can't be deleted while they are exported
+    let lease = runtime_usage
+        .acquire(id)
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire runtime lease: {e}")))?
+        .ok_or_else(|| ApiError::conflict(format!("Runtime with id: {id} is being deleted")))?;
+    let runtime = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Could not find the specified runtime"))?;
+    let bundle = Bundle {
+        format_version: BUNDLE_FORMAT_VERSION,
+        runtimes: vec![bundle_runtime(id, &runtime).await?],
+    };
+
+    let archive_dir = create_temp_dir(&box_id_allocator, cleaner)
+        .await
+        .map_err(ApiError::Internal)?;
+    write_archive(
+        &get_version_dir(id, runtime.version),
+        &archive_dir.path,
+        &bundle,
+        installation_timeout,
+    )
+    .await
+    .map_err(ApiError::Internal)?;
+    drop(lease);
+
+    let archive_path = format!("{}/{ARCHIVE_NAME}", archive_dir.path);
+    let archive = fs::File::open(&archive_path)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to open {archive_path}\nError: {e}")))?;
+    // The directory is removed once the whole archive has been sent
+    let stream = stream::unfold(Some((archive, archive_dir)), |state| async move {
+        let (mut archive, archive_dir) = state?;
+        let mut buf = vec![0; ARCHIVE_CHUNK_SIZE];
+        match archive.read(&mut buf).await {
+            Ok(0) => {
+                archive_dir.close().await;
+                None
+            }
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(buf), Some((archive, archive_dir))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"runtime-{id}.tar\""),
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+async fn save_body(body: Body, path: &str) -> Result<(), ApiError> {
+    let mut file = fs::File::create(path)
+        .await
+        .map_err(|e| anyhow!("Failed to create {path}\nError: {e}"))?;
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| bad_archive(format!("failed to read the upload: {e}")))?;
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| anyhow!("Failed to write {path}\nError: {e}"))?;
+    }
+    file.flush()
+        .await
+        .map_err(|e| anyhow!("Failed to write {path}\nError: {e}"))?;
+    Ok(())
+}
+
+/// Extracts only the expected members of the archive and makes sure they are regular files
+async fn extract_archive(archive_dir: &str) -> Result<(), ApiError> {
+    let archive_path = format!("{archive_dir}/{ARCHIVE_NAME}");
+    let members = run_tar(&["-tf", &archive_path])
+        .await
+        .map_err(|e| bad_archive(e.to_string()))?;
+    let members: Vec<&str> = [
+        BUNDLE_FILE,
+        ENV_FILE,
+        LEGACY_ENV_FILE,
+        CLOSURE_FILE,
+        LOCK_FILE,
+    ]
+    .into_iter()
+    .filter(|file| members.lines().any(|member| member == *file))
+    .collect();
+    for required in [BUNDLE_FILE, CLOSURE_FILE] {
+        if !members.contains(&required) {
+            return Err(bad_archive(format!("{required} is missing")));
+        }
+    }
+    // Archives exported before env.json have the output of `env` instead
+    if !members.contains(&ENV_FILE) && !members.contains(&LEGACY_ENV_FILE) {
+        return Err(bad_archive(format!("{ENV_FILE} is missing")));
+    }
+    let mut args = vec!["-xf", &archive_path, "-C", archive_dir, "--no-same-owner"];
+    args.extend(&members);
+    run_tar(&args)
+        .await
+        .map_err(|e| bad_archive(e.to_string()))?;
+    for member in members {
+        let path = format!("{archive_dir}/{member}");
+        let metadata = fs::symlink_metadata(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to stat {path}\nError: {e}"))?;
+        if !metadata.is_file() {
+            return Err(bad_archive(format!("{member} is not a regular file")));
+        }
+    }
+    Ok(())
+}
+
+/// Installs a runtime from an archive made by `export_runtime_closure` without network access,
+/// the archive is validated before the installation starts in the background
+#[allow(clippy::too_many_arguments)]
+pub async fn import_runtime_closure(
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_jobs: Arc<InstallationJobs>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    body: Body,
+) -> Result<Response<Body>, Response<Body>> {
+    let archive_dir = create_temp_dir(&box_id_allocator, cleaner.clone())
+        .await
+        .map_err(ApiError::Internal)?;
+    save_body(body, &format!("{}/{ARCHIVE_NAME}", archive_dir.path)).await?;
+    extract_archive(&archive_dir.path).await?;
+
+    let bundle_path = format!("{}/{BUNDLE_FILE}", archive_dir.path);
+    let bundle = fs::read_to_string(&bundle_path)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to read {bundle_path}\nError: {e}")))?;
+    let bundle: Bundle =
+        serde_json::from_str(&bundle).map_err(|e| bad_archive(format!("{BUNDLE_FILE}: {e}")))?;
+    if bundle.format_version != BUNDLE_FORMAT_VERSION {
+        return Err(bad_archive(format!(
+            "unsupported bundle format version: {}, expected: {BUNDLE_FORMAT_VERSION}",
+            bundle.format_version
+        ))
+        .into());
+    }
+    let Ok([mut runtime]) = <[_; 1]>::try_from(bundle.runtimes) else {
+        return Err(bad_archive("expected exactly one runtime".to_string()).into());
+    };
+    prepare_request(&mut runtime.definition, &system_limits).await?;
+    prepare_tags(&mut runtime.tags)?;
+    let name_reservation = NameReservation::new(
+        &runtime.definition.name,
+        &installations,
+        &metadata_cache,
+        &aliases_cache,
+    )
+    .await?;
+
+    let id = start_installation(
+        installation_timeout,
+        box_id_allocator,
+        cleaner,
+        metadata_cache,
+        installation_jobs,
+        installation_lock,
+        system_limits,
+        name_reservation,
+        runtime.definition,
+        runtime.tags,
+        EnvironmentSource::Closure(archive_dir),
+    )
+    .await?;
+    Ok((StatusCode::ACCEPTED, Json(InstallationJobResponse { id })).into_response())
+}
+
+/// Imports the closure of an extracted archive into the store and copies its lock to the runtime,
+/// returns the environment of the runtime and the store paths it uses
+pub async fn import_closure_archive(
+    job: &InstallationJob,
+    archive_dir: &str,
+    runtime_dir: &str,
+    installation_timeout: WholeSeconds,
+) -> Result<(Env, Vec<String>), Error> {
+    job.log("Importing the Nix closure").await;
+    nix::import_closure(
+        &format!("{archive_dir}/{CLOSURE_FILE}"),
+        installation_timeout,
+    )
+    .await?;
+    nix::copy_lock(archive_dir, runtime_dir).await?;
+    let env = runtime_env::read_env(archive_dir).await?;
+    let store_paths = runtime_env::find_store_paths(&env);
+    job.log(&format!("Imported {} store path(s)", store_paths.len()))
+        .await;
+    Ok((env, store_paths))
+}
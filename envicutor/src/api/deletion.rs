@@ -1,57 +1,155 @@
-use std::sync::Arc;
+use std::{io, sync::Arc, time::Duration};
 
+use anyhow::{anyhow, Error};
 use axum::{
     body::Body,
-    extract::Path,
-    http::StatusCode,
+    extract::{Path, Query},
     response::{IntoResponse, Response},
     Json,
 };
 use rusqlite::Connection;
-use tokio::{sync::RwLock, task};
+use serde::Deserialize;
+use tokio::{fs, sync::RwLock, task};
 
 use crate::{
-    api::common_responses::{StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
-    globals::DB_PATH,
-    types::Metadata,
+    api::errors::ApiError,
+    cleanup::{Cleaner, CleanupTask},
+    globals::{DB_PATH, RUNTIMES_DIR},
+    nix,
+    runtime_usage::{DrainError, RuntimeUsage},
+    types::{Aliases, Metadata, WholeSeconds},
 };
 
+#[derive(Deserialize)]
+pub struct DeletionQuery {
+    collect_garbage: bool,
+}
+
+async fn rename_if_exists(from: &str, to: &str) -> Result<bool, Error> {
+    match fs::rename(from, to).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(anyhow!("Failed to move {from} to {to}: {e}")),
+    }
+}
+
 pub async fn delete_runtime(
     Path(id): Path<u32>,
+    query: Option<Query<DeletionQuery>>,
+    deletion_timeout: WholeSeconds,
+    cleaner: Arc<Cleaner>,
     metadata_cache: Arc<RwLock<Metadata>>,
-) -> Result<(), Response<Body>> {
-    let affected_rows = task::spawn_blocking(move || {
-        let conn = Connection::open(DB_PATH).map_err(|e| {
-            eprintln!("Failed to open SQLite connection: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
-        let mut stmt = conn
-            .prepare("DELETE FROM runtime WHERE id = ?")
-            .map_err(|e| {
-                eprintln!("Failed to open SQLite connection: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
-        let affected_rows = stmt.execute([id]).map_err(|e| {
-            eprintln!("Failed to prepare SQLite statement: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    aliases_cache: Arc<RwLock<Aliases>>,
+    runtime_usage: Arc<RuntimeUsage>,
+) -> Result<Response<Body>, Response<Body>> {
+    remove_runtime(
+        id,
+        deletion_timeout,
+        &cleaner,
+        &metadata_cache,
+        &aliases_cache,
+        &runtime_usage,
+    )
+    .await?;
+
+    let collect_garbage = if let Some(query) = query {
+        query.collect_garbage
+    } else {
+        false
+    };
+    if !collect_garbage {
+        return Ok(().into_response());
+    }
+    // Waits for installations and Nix updates to root their store paths, executions go on
+    let gc_result = nix::collect_garbage(deletion_timeout)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to collect garbage: {e}")))?;
+    Ok(Json(gc_result).into_response())
+}
+
+/// Waits for the executions of the runtime to finish, then deletes it with its versions,
+/// aliases and tags. Its directory is removed in the background
+pub async fn remove_runtime(
+    id: u32,
+    deletion_timeout: WholeSeconds,
+    cleaner: &Arc<Cleaner>,
+    metadata_cache: &Arc<RwLock<Metadata>>,
+    aliases_cache: &Arc<RwLock<Aliases>>,
+    runtime_usage: &Arc<RuntimeUsage>,
+) -> Result<(), ApiError> {
+    let _drain_guard = runtime_usage
+        .drain(id, Duration::from_secs(deletion_timeout.into()))
+        .await
+        .map_err(|e| match e {
+            DrainError::AlreadyDraining => {
+                ApiError::conflict("The runtime is already being deleted")
+            }
+            DrainError::Timeout(in_flight) => ApiError::conflict(format!(
+                "The runtime is still being used by {in_flight} execution(s)"
+            )),
+            DrainError::Internal(e) => ApiError::Internal(anyhow!("Failed to drain runtime: {e}")),
         })?;
+
+    // The runtime directory is moved aside first so it can be restored if the row can't be deleted
+    let runtime_dir = format!("{RUNTIMES_DIR}/{id}");
+    let deleted_runtime_dir = format!("{runtime_dir}.deleting");
+    let moved = rename_if_exists(&runtime_dir, &deleted_runtime_dir).await?;
+
+    let deletion_res = task::spawn_blocking(move || {
+        let mut conn = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        // The runtime and its versions, aliases and tags are deleted together or not at all
+        let trx = conn
+            .transaction()
+            .map_err(|e| anyhow!("Failed to start SQLite transaction: {e}"))?;
+        let affected_rows = trx
+            .execute("DELETE FROM runtime WHERE id = ?", [id])
+            .map_err(|e| anyhow!("Failed to delete runtime with id: {id}: {e}"))?;
+        trx.execute("DELETE FROM runtime_version WHERE runtime_id = ?", [id])
+            .map_err(|e| anyhow!("Failed to delete versions of runtime with id: {id}: {e}"))?;
+        trx.execute("DELETE FROM runtime_alias WHERE runtime_id = ?", [id])
+            .map_err(|e| anyhow!("Failed to delete aliases of runtime with id: {id}: {e}"))?;
+        trx.execute("DELETE FROM runtime_tag WHERE runtime_id = ?", [id])
+            .map_err(|e| anyhow!("Failed to delete tags of runtime with id: {id}: {e}"))?;
+        trx.commit()
+            .map_err(|e| anyhow!("Failed to commit SQLite transaction: {e}"))?;
         Ok(affected_rows)
     })
     .await
-    .map_err(|e| {
-        eprintln!("Failed to spawn blocking task: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })??;
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res);
+    let affected_rows = match deletion_res {
+        Ok(affected_rows) => affected_rows,
+        Err(e) => {
+            if moved {
+                rename_if_exists(&deleted_runtime_dir, &runtime_dir).await?;
+            }
+            return Err(ApiError::Internal(e));
+        }
+    };
     if affected_rows == 0 {
-        return Err((
-            StatusCode::NOT_FOUND,
-            Json(StaticMessage {
-                message: "Could not find the specified runtime",
-            }),
-        )
-            .into_response());
+        if moved {
+            rename_if_exists(&deleted_runtime_dir, &runtime_dir).await?;
+        }
+        return Err(ApiError::not_found("Could not find the specified runtime"));
     }
+
     let mut metadata_guard = metadata_cache.write().await;
     metadata_guard.remove(&id);
+    drop(metadata_guard);
+    aliases_cache
+        .write()
+        .await
+        .retain(|_, runtime_id| *runtime_id != id);
+
+    // Removing the directory also removes the GC roots that were registered inside it
+    if moved {
+        cleaner
+            .run(CleanupTask::Directory {
+                path: deleted_runtime_dir,
+                box_id_guard: None,
+            })
+            .await;
+    }
     Ok(())
 }
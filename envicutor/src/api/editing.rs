@@ -0,0 +1,607 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error};
+use axum::{
+    body::Body,
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, process::Command, sync::RwLock, task};
+
+use crate::{
+    api::{
+        errors::ApiError,
+        installation::{
+            read_script, validate_request, write_runtime_limits, write_runtime_scripts,
+            AddRuntimeRequest, InstallationJobResponse, InstallationResponse, NameReservation,
+        },
+    },
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    closure_mounts::write_closure_mounts,
+    globals::{DB_PATH, STAGING_DIR},
+    installation_jobs::{InstallationJob, InstallationJobs},
+    limits::{RuntimeLimits, SystemLimits},
+    nix::{self, NixpkgsPin},
+    runtime_env::{
+        parse_env_output, read_env, read_env_settings, write_env, write_env_settings, EnvSettings,
+    },
+    runtime_tests::{read_tests, run_tests, write_tests, RuntimeTest},
+    runtime_usage::RuntimeUsage,
+    runtime_versions::{activate_version, get_next_version, get_version_dir},
+    store_usage::write_closure_sizes,
+    strings::NewLine,
+    temp_dir::TempDir,
+    transaction::Transaction,
+    types::{Aliases, Metadata, Runtime, WholeSeconds},
+};
+
+#[derive(Serialize)]
+pub struct EditResponse {
+    version: u32,
+}
+
+#[derive(Deserialize)]
+pub struct PatchRuntimeRequest {
+    name: Option<String>,
+    nix_shell: Option<String>,
+    flake: Option<String>,
+    nixpkgs: Option<NixpkgsPin>,
+    compile_script: Option<String>,
+    run_script: Option<String>,
+    source_file_name: Option<String>,
+    limits: Option<RuntimeLimits>,
+    tests: Option<Vec<RuntimeTest>>,
+    env_settings: Option<EnvSettings>,
+}
+
+pub async fn get_current_definition(
+    runtime_dir: &str,
+    runtime: &Runtime,
+) -> Result<AddRuntimeRequest, Error> {
+    let nix_shell_path = format!("{runtime_dir}/shell.nix");
+    let nix_source = nix::read_source(runtime_dir).await?;
+    Ok(AddRuntimeRequest {
+        name: runtime.name.clone(),
+        nix_shell: fs::read_to_string(&nix_shell_path)
+            .await
+            .map_err(|e| anyhow!("Failed to read {nix_shell_path}\nError: {e}"))?,
+        flake: nix_source.flake,
+        nixpkgs: nix_source.nixpkgs,
+        compile_script: read_script(&format!("{runtime_dir}/compile")).await?,
+        run_script: read_script(&format!("{runtime_dir}/run")).await?,
+        source_file_name: runtime.source_file_name.clone(),
+        limits: runtime.limits.clone(),
+        tests: read_tests(runtime_dir).await?,
+        env_settings: read_env_settings(runtime_dir).await?,
+    })
+}
+
+/// Replaces the whole definition of a runtime with a new version, keeping its ID
+#[allow(clippy::too_many_arguments)]
+pub async fn replace_runtime(
+    Path(id): Path<u32>,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    Json(req): Json<AddRuntimeRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    edit_runtime(
+        id,
+        installation_timeout,
+        box_id_allocator,
+        cleaner,
+        metadata_cache,
+        runtime_usage,
+        installations,
+        aliases_cache,
+        installation_lock,
+        system_limits,
+        false,
+        |_| req,
+    )
+    .await
+    .map(|version| Json(EditResponse { version }).into_response())
+    .map_err(IntoResponse::into_response)
+}
+
+/// Creates a new version of a runtime with only the given fields changed, keeping its ID
+#[allow(clippy::too_many_arguments)]
+pub async fn patch_runtime(
+    Path(id): Path<u32>,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    Json(patch): Json<PatchRuntimeRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    edit_runtime(
+        id,
+        installation_timeout,
+        box_id_allocator,
+        cleaner,
+        metadata_cache,
+        runtime_usage,
+        installations,
+        aliases_cache,
+        installation_lock,
+        system_limits,
+        false,
+        |current| {
+            // Switching between a shell and a flake drops the definition of the other one
+            let (nix_shell, flake, nixpkgs) = match (patch.nix_shell, patch.flake) {
+                (Some(nix_shell), None) => (nix_shell, None, patch.nixpkgs.or(current.nixpkgs)),
+                (None, Some(flake)) => (String::new(), Some(flake), patch.nixpkgs),
+                (nix_shell, flake) => (
+                    nix_shell.unwrap_or(current.nix_shell),
+                    flake.or(current.flake),
+                    patch.nixpkgs.or(current.nixpkgs),
+                ),
+            };
+            AddRuntimeRequest {
+                name: patch.name.unwrap_or(current.name),
+                nix_shell,
+                flake,
+                nixpkgs,
+                compile_script: patch.compile_script.unwrap_or(current.compile_script),
+                run_script: patch.run_script.unwrap_or(current.run_script),
+                source_file_name: patch.source_file_name.unwrap_or(current.source_file_name),
+                limits: patch.limits.unwrap_or(current.limits),
+                tests: patch.tests.unwrap_or(current.tests),
+                env_settings: patch.env_settings.unwrap_or(current.env_settings),
+            }
+        },
+    )
+    .await
+    .map(|version| Json(EditResponse { version }).into_response())
+    .map_err(IntoResponse::into_response)
+}
+
+/// Re-evaluates the definition of a runtime into a new version, e.g. after updating Nix.
+/// The new version is only activated if it builds and its tests pass
+#[allow(clippy::too_many_arguments)]
+pub async fn rebuild_runtime(
+    Path(id): Path<u32>,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+) -> Result<Response<Body>, Response<Body>> {
+    edit_runtime(
+        id,
+        installation_timeout,
+        box_id_allocator,
+        cleaner,
+        metadata_cache,
+        runtime_usage,
+        installations,
+        aliases_cache,
+        installation_lock,
+        system_limits,
+        true,
+        |current| current,
+    )
+    .await
+    .map(|version| Json(EditResponse { version }).into_response())
+    .map_err(IntoResponse::into_response)
+}
+
+/// What the jobs of rebuilds of all runtimes are named among the installations
+const REBUILD_JOB_NAME: &str = "Rebuild of all runtimes";
+
+/// Rebuilds the runtimes one after the other in the background, a failure leaves the previous
+/// version of that runtime active and doesn't stop the others.
+/// Its progress is followed like an installation's with the ID of the returned job
+#[allow(clippy::too_many_arguments)]
+pub async fn rebuild_runtimes(
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_jobs: Arc<InstallationJobs>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+) -> Result<Response<Body>, Response<Body>> {
+    let job = installation_jobs
+        .create(REBUILD_JOB_NAME)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to create installation job: {e}")))?;
+    let id = job.id;
+
+    tokio::spawn(async move {
+        let res = run_rebuilds(
+            &job,
+            installation_timeout,
+            box_id_allocator,
+            cleaner,
+            metadata_cache,
+            runtime_usage,
+            installations,
+            aliases_cache,
+            installation_lock,
+            system_limits,
+        )
+        .await;
+        installation_jobs.finish(&job, res.map(|()| None)).await;
+    });
+    Ok((StatusCode::ACCEPTED, Json(InstallationJobResponse { id })).into_response())
+}
+
+/// Cancelling the job stops it once the runtime being rebuilt is done
+#[allow(clippy::too_many_arguments)]
+async fn run_rebuilds(
+    job: &InstallationJob,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+) -> Result<(), Error> {
+    let mut runtimes: Vec<(u32, String)> = metadata_cache
+        .read()
+        .await
+        .iter()
+        .map(|(id, runtime)| (*id, runtime.name.clone()))
+        .collect();
+    runtimes.sort_by_key(|(id, _)| *id);
+
+    let total = runtimes.len();
+    let mut failed = 0;
+    for (i, (id, name)) in runtimes.into_iter().enumerate() {
+        if job.is_cancelled() {
+            return Err(anyhow!(
+                "Cancelled after rebuilding {i} of {total} runtime(s)"
+            ));
+        }
+        job.log(&format!(
+            "[{}/{total}] Rebuilding runtime {name} with id: {id}",
+            i + 1
+        ))
+        .await;
+        let res = edit_runtime(
+            id,
+            installation_timeout,
+            box_id_allocator.clone(),
+            cleaner.clone(),
+            metadata_cache.clone(),
+            runtime_usage.clone(),
+            installations.clone(),
+            aliases_cache.clone(),
+            installation_lock.clone(),
+            system_limits.clone(),
+            true,
+            |current| current,
+        )
+        .await;
+        match res {
+            Ok(version) => {
+                job.log(&format!("Rebuilt runtime {name} into version {version}"))
+                    .await
+            }
+            Err(e) => {
+                failed += 1;
+                job.log(&format!(
+                    "Failed to rebuild runtime {name}: {}",
+                    e.into_message()
+                ))
+                .await;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {total} runtime(s) failed to rebuild"));
+    }
+    Ok(())
+}
+
+/// Builds a new version of a runtime from its current definition and activates it,
+/// returns the new version
+#[allow(clippy::too_many_arguments)]
+pub async fn edit_runtime(
+    id: u32,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    rebuild: bool,
+    get_new_definition: impl FnOnce(AddRuntimeRequest) -> AddRuntimeRequest,
+) -> Result<u32, ApiError> {
+    // Only Nix updates need to exclude edits
+    let permit = installation_lock.read().await;
+    // Garbage collection must not remove the store paths of the new shell before they are rooted
+    let gc_guard = nix::hold_off_garbage_collection().await;
+
+    // Held for the whole edit so the runtime can't be deleted in the meantime
+    let _lease = runtime_usage
+        .acquire(id)?
+        .ok_or_else(|| ApiError::conflict(format!("Runtime with id: {id} is being deleted")))?;
+    let current_runtime = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Could not find the specified runtime"))?;
+    let _current_name_reservation =
+        NameReservation::reserve(&current_runtime.name, &installations)?
+            .ok_or_else(|| ApiError::conflict("The runtime is already being edited"))?;
+
+    let current_version_dir = get_version_dir(id, current_runtime.version);
+    let current = get_current_definition(&current_version_dir, &current_runtime).await?;
+    // The environment is captured again when its settings change since it's stored filtered
+    let current_nix_definition = (
+        current.nix_shell.clone(),
+        current.nix_source(),
+        current.env_settings.clone(),
+    );
+    let mut req = get_new_definition(current);
+    validate_request(&req, &system_limits).await?;
+    req.nix_shell.add_new_line_if_none();
+    req.compile_script.add_new_line_if_none();
+    req.run_script.add_new_line_if_none();
+
+    let environment_changed = rebuild
+        || (
+            req.nix_shell.clone(),
+            req.nix_source(),
+            req.env_settings.clone(),
+        ) != current_nix_definition;
+
+    let _new_name_reservation = if req.name != current_runtime.name {
+        Some(
+            NameReservation::new(&req.name, &installations, &metadata_cache, &aliases_cache)
+                .await?,
+        )
+    } else {
+        None
+    };
+
+    let current_box_id = box_id_allocator
+        .allocate()
+        .map_err(|e| anyhow!("Failed to allocate a box ID: {e}"))?;
+    let staging_dir = TempDir::new(
+        format!("{STAGING_DIR}/{}", current_box_id.get()),
+        Some(current_box_id),
+        cleaner.clone(),
+    )
+    .await
+    .map_err(|e| anyhow!("Failed to create staging directory: {e}"))?;
+
+    let res = edit_in_staging_dir(
+        id,
+        &staging_dir,
+        &current_version_dir,
+        installation_timeout,
+        &box_id_allocator,
+        cleaner,
+        &metadata_cache,
+        &system_limits,
+        current_runtime,
+        environment_changed,
+        req,
+    )
+    .await;
+    staging_dir.close().await;
+    let version = res?;
+    drop(gc_guard);
+    drop(permit);
+
+    if environment_changed {
+        // Sharing the environment is best effort, the edit doesn't wait for it
+        let version_dir = get_version_dir(id, version);
+        tokio::spawn(async move {
+            if let Err(e) = nix::upload_to_cache(&version_dir, installation_timeout).await {
+                eprintln!(
+                    "Failed to upload the environment of runtime with id: {id} to the cache: {e}"
+                );
+            }
+        });
+    }
+    Ok(version)
+}
+
+/// Builds the new version in the staging directory, moves it next to the previous versions,
+/// then makes it the active version
+#[allow(clippy::too_many_arguments)]
+async fn edit_in_staging_dir(
+    id: u32,
+    staging_dir: &TempDir,
+    current_version_dir: &str,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: &Arc<RwLock<Metadata>>,
+    system_limits: &SystemLimits,
+    current_runtime: Runtime,
+    environment_changed: bool,
+    req: AddRuntimeRequest,
+) -> Result<u32, ApiError> {
+    write_runtime_scripts(&staging_dir.path, &req).await?;
+    write_runtime_limits(&staging_dir.path, &req.limits).await?;
+    write_tests(&staging_dir.path, &req.tests).await?;
+    write_env_settings(&staging_dir.path, &req.env_settings).await?;
+    let nix_shell_path = format!("{}/shell.nix", staging_dir.path);
+    fs::write(&nix_shell_path, &req.nix_shell)
+        .await
+        .map_err(|e| anyhow!("Could not write nix shell file: {e}"))?;
+
+    nix::write_source(&staging_dir.path, &req.nix_source()).await?;
+
+    if environment_changed {
+        nix::lock_source(&staging_dir.path, installation_timeout)
+            .await
+            .map_err(|e| ApiError::bad_request(format!("Failed to lock the Nix source: {e}")))?;
+        let cmd_res =
+            Command::from(nix::env_std_command(&staging_dir.path, installation_timeout).await?)
+                .output()
+                .await
+                .map_err(|e| anyhow!("Failed to run nix-shell: {e}"))?;
+        if !cmd_res.status.success() {
+            let stdout = String::from_utf8_lossy(&cmd_res.stdout).to_string();
+            let stderr = String::from_utf8_lossy(&cmd_res.stderr).to_string();
+            return Err(ApiError::BuildFailed(InstallationResponse {
+                stdout,
+                stderr,
+            }));
+        }
+        let env = parse_env_output(&cmd_res.stdout)?;
+        write_env(&staging_dir.path, &req.env_settings.apply(env)).await?;
+    } else {
+        // The store paths stay rooted by the previous version, which is kept
+        let env = read_env(current_version_dir).await?;
+        write_env(&staging_dir.path, &env).await?;
+        nix::copy_lock(current_version_dir, &staging_dir.path).await?;
+    }
+    write_closure_mounts(&staging_dir.path).await?;
+    write_closure_sizes(&staging_dir.path).await?;
+
+    let runtime_name = req.name.clone();
+    let source_file_name = req.source_file_name.clone();
+    let tests_cleaner = cleaner.clone();
+    let (version, mut trx) = task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        let version = get_next_version(&connection, id)?;
+        connection
+            .execute(
+                "INSERT INTO runtime_version (runtime_id, version, source_file_name) VALUES (?, ?, ?)",
+                (id, version, &source_file_name),
+            )
+            .map_err(|e| anyhow!("Failed to insert version {version} of runtime with id: {id}\nError: {e}"))?;
+        let trx = Transaction::init(cleaner, move |conn| {
+            conn.execute(
+                "UPDATE runtime SET name = ? WHERE id = ?",
+                (&current_runtime.name, id),
+            )
+            .map_err(|e| {
+                anyhow!("Failed to restore runtime with id: {id} during rollback\nError: {e}")
+            })?;
+            activate_version(conn, id, current_runtime.version)?;
+            conn.execute(
+                "DELETE FROM runtime_version WHERE runtime_id = ? AND version = ?",
+                (id, version),
+            )
+            .map_err(|e| {
+                anyhow!("Failed to remove version {version} of runtime with id: {id} during rollback\nError: {e}")
+            })?;
+            Ok(())
+        });
+        Ok::<_, Error>((version, trx))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+
+    let version_dir = get_version_dir(id, version);
+    if let Err(e) = fs::rename(&staging_dir.path, &version_dir).await {
+        trx.close().await;
+        return Err(ApiError::Internal(anyhow!(
+            "Failed to move {} to {version_dir}: {e}",
+            staging_dir.path
+        )));
+    }
+    let activation_res = if environment_changed {
+        nix::add_gc_roots(&version_dir, installation_timeout)
+            .await
+            .map_err(|e| anyhow!("Failed to register GC roots: {e}"))
+    } else {
+        Ok(())
+    };
+    let runtime = Runtime {
+        name: req.name,
+        is_compiled: !req.compile_script.is_empty(),
+        source_file_name: req.source_file_name,
+        version,
+        tags: current_runtime.tags,
+        limits: req.limits,
+    };
+    // The new version is only activated if all of its tests pass
+    let tests_res = match activation_res {
+        Ok(()) if req.tests.is_empty() => Ok(None),
+        Ok(()) => run_tests(
+            box_id_allocator,
+            &tests_cleaner,
+            id,
+            &runtime,
+            &req.tests,
+            system_limits,
+        )
+        .await
+        .map(Some),
+        Err(e) => Err(e),
+    };
+    let activation_res = match tests_res {
+        Ok(Some(report)) if !report.passed => {
+            if let Err(e) = crate::fs::remove_dir_if_exists(&version_dir).await {
+                eprintln!("{e}");
+            }
+            trx.close().await;
+            return Err(ApiError::TestsFailed(report));
+        }
+        Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    };
+    let activation_res = match activation_res {
+        Ok(()) => task::spawn_blocking(move || {
+            let connection = Connection::open(DB_PATH)
+                .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+            connection
+                .execute(
+                    "UPDATE runtime SET name = ? WHERE id = ?",
+                    (&runtime_name, id),
+                )
+                .map_err(|e| anyhow!("Failed to update runtime with id: {id}\nError: {e}"))?;
+            activate_version(&connection, id, version)
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+        .and_then(|res| res),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = activation_res {
+        if let Err(e) = crate::fs::remove_dir_if_exists(&version_dir).await {
+            eprintln!("{e}");
+        }
+        trx.close().await;
+        return Err(ApiError::Internal(e));
+    }
+
+    let mut metadata_guard = metadata_cache.write().await;
+    metadata_guard.insert(id, runtime);
+    drop(metadata_guard);
+    trx.commit();
+    Ok(version)
+}
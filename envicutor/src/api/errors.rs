@@ -0,0 +1,108 @@
+use std::fmt;
+
+use anyhow::Error;
+use axum::{
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    api::{
+        common_responses::{Message, INTERNAL_SERVER_ERROR_RESPONSE},
+        installation::InstallationResponse,
+    },
+    runtime_tests::TestReport,
+};
+
+/// An error of an operation on runtimes, shared by the handlers that respond with it
+/// and the manifests sync that reports it
+pub enum ApiError {
+    /// Caused by the request, the message is returned with the status code
+    Rejected(StatusCode, String),
+    /// The Nix shell of the runtime failed to build
+    BuildFailed(InstallationResponse),
+    TestsFailed(TestReport),
+    /// Logged, only a generic message is returned
+    Internal(Error),
+}
+
+#[derive(Serialize)]
+struct FailedTestsResponse {
+    message: &'static str,
+    tests: TestReport,
+}
+
+impl ApiError {
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::Rejected(StatusCode::BAD_REQUEST, message.into())
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::Rejected(StatusCode::CONFLICT, message.into())
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::Rejected(StatusCode::NOT_FOUND, message.into())
+    }
+
+    /// What the error is reported as outside of a response,
+    /// internal errors are logged and hidden like in responses
+    pub fn into_message(self) -> String {
+        match self {
+            Self::Internal(e) => {
+                eprintln!("{e}");
+                "Internal server error".to_string()
+            }
+            e => e.to_string(),
+        }
+    }
+}
+
+/// Includes the cause of internal errors, for errors that are logged or wrapped
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(_, message) => write!(f, "{message}"),
+            Self::BuildFailed(res) => write!(f, "Failed to build the Nix shell\n{}", res.stderr),
+            Self::TestsFailed(_) => write!(f, "Smoke tests failed"),
+            Self::Internal(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        Self::Internal(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        match self {
+            Self::Rejected(status, message) => (status, Json(Message { message })).into_response(),
+            Self::BuildFailed(res) => (StatusCode::BAD_REQUEST, Json(res)).into_response(),
+            Self::TestsFailed(tests) => (
+                StatusCode::BAD_REQUEST,
+                Json(FailedTestsResponse {
+                    message: "Smoke tests failed",
+                    tests,
+                }),
+            )
+                .into_response(),
+            Self::Internal(e) => {
+                eprintln!("{e}");
+                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            }
+        }
+    }
+}
+
+/// Lets handlers use `?` on operations returning an `ApiError`
+impl From<ApiError> for Response<Body> {
+    fn from(e: ApiError) -> Self {
+        e.into_response()
+    }
+}
@@ -1,10 +1,9 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Error};
 use axum::{
     body::Body,
     extract::Query,
-    http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
@@ -17,13 +16,16 @@ use tokio::{
 };
 
 use crate::{
-    api::common_functions::get_next_box_id,
-    api::common_responses::{Message, INTERNAL_SERVER_ERROR_RESPONSE},
-    globals::RUNTIMES_DIR,
+    api::errors::ApiError,
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    closure_mounts::read_closure_mounts,
     isolate::{Isolate, StageResult},
-    limits::{GetLimits, Limits, SystemLimits},
+    limits::{with_defaults, GetLimits, Limits, MandatoryLimits, SystemLimits},
+    runtime_usage::RuntimeUsage,
+    runtime_versions::{get_version_dir, load_version},
     strings::NewLine,
-    types::Metadata,
+    types::{Aliases, Metadata, Runtime},
 };
 
 const SOURCE_ZIP_NAME: &str = "source.zip";
@@ -35,7 +37,9 @@ pub struct ExecutionQuery {
 
 #[derive(Deserialize)]
 pub struct ExecutionRequest {
-    runtime_id: u32,
+    runtime_id: Option<u32>,
+    runtime: Option<String>,
+    runtime_version: Option<u32>,
     source_code: String,
     input: Option<String>,
     compile_limits: Option<Limits>,
@@ -44,13 +48,17 @@ pub struct ExecutionRequest {
 
 #[derive(Serialize)]
 pub struct ExecutionResponse {
-    extract: Option<StageResult>,
-    compile: Option<StageResult>,
-    run: Option<StageResult>,
+    pub extract: Option<StageResult>,
+    pub compile: Option<StageResult>,
+    pub run: Option<StageResult>,
 }
 
-pub async fn renew_box(box_id: &Arc<AtomicU64>, execution_box: &mut Isolate) -> Result<(), Error> {
-    let new_box = Isolate::init(get_next_box_id(box_id))
+pub async fn renew_box(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: &Arc<Cleaner>,
+    execution_box: &mut Isolate,
+) -> Result<(), Error> {
+    let new_box = Isolate::init(box_id_allocator.allocate()?, cleaner.clone())
         .await
         .map_err(|e| anyhow!("Failed to initialize run sandbox: {e}"))?;
     fs::rename(
@@ -66,186 +74,259 @@ pub async fn renew_box(box_id: &Arc<AtomicU64>, execution_box: &mut Isolate) ->
             e
         )
     })?;
-    *execution_box = new_box;
+    let old_box = std::mem::replace(execution_box, new_box);
+    old_box.close().await;
     Ok(())
 }
 
+/// Resolves `runtime_id`, or `runtime` as a runtime name or an alias, runtime names take precedence
+fn resolve_runtime_id(
+    req: &ExecutionRequest,
+    metadata: &Metadata,
+    aliases: &Aliases,
+) -> Result<u32, ApiError> {
+    match (req.runtime_id, &req.runtime) {
+        (Some(id), None) => Ok(id),
+        (None, Some(name)) => metadata
+            .iter()
+            .find(|(_, runtime)| &runtime.name == name)
+            .map(|(id, _)| *id)
+            .or_else(|| aliases.get(name).copied())
+            .ok_or_else(|| {
+                ApiError::bad_request(format!("Runtime with name or alias: {name} does not exist"))
+            }),
+        (Some(_), Some(_)) => Err(ApiError::bad_request(
+            "Only one of runtime_id and runtime can be specified",
+        )),
+        (None, None) => Err(ApiError::bad_request(
+            "Either runtime_id or runtime must be specified",
+        )),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn execute(
     semaphore: Arc<Semaphore>,
-    box_id: Arc<AtomicU64>,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
     metadata_cache: Arc<RwLock<Metadata>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    runtime_usage: Arc<RuntimeUsage>,
     installation_lock: Arc<RwLock<u8>>,
     system_limits: SystemLimits,
-    Json(mut req): Json<ExecutionRequest>,
+    Json(req): Json<ExecutionRequest>,
     query: Option<Query<ExecutionQuery>>,
 ) -> Result<Response<Body>, Response<Body>> {
     let _installation_guard = installation_lock.read().await;
-    let _permit = semaphore.acquire().await.map_err(|e| {
-        eprintln!("Failed to acquire execution semaphore: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })?;
+    let _permit = semaphore
+        .acquire()
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire execution semaphore: {e}")))?;
     let is_project = if let Some(query) = query {
         query.is_project
     } else {
         false
     };
-    let compile_limits = req
-        .compile_limits
-        .get(&system_limits.compile)
-        .map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                Json(Message {
-                    message: format!("Invalid compile limits: {e}"),
-                }),
-            )
-                .into_response()
-        })?;
-    let run_limits = req.run_limits.get(&system_limits.run).map_err(|e| {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(Message {
-                message: format!("Invalid run limits: {e}"),
-            }),
-        )
-            .into_response()
-    })?;
-
+    let aliases_guard = aliases_cache.read().await;
     let metadata_guard = metadata_cache.read().await;
-    let runtime = metadata_guard.get(&req.runtime_id).ok_or_else(|| {
-        (
-            StatusCode::BAD_REQUEST,
-            Json(Message {
-                message: format!("Runtime with id: {} does not exist", req.runtime_id),
-            }),
-        )
-            .into_response()
+    let runtime_id = resolve_runtime_id(&req, &metadata_guard, &aliases_guard)?;
+    drop(aliases_guard);
+    let runtime = metadata_guard.get(&runtime_id).cloned().ok_or_else(|| {
+        ApiError::bad_request(format!("Runtime with id: {runtime_id} does not exist"))
     })?;
+    // The lease is acquired before releasing the cache so a deletion can't sneak in between
+    let _runtime_lease = runtime_usage
+        .acquire(runtime_id)
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire runtime lease: {e}")))?
+        .ok_or_else(|| {
+            ApiError::conflict(format!("Runtime with id: {runtime_id} is being deleted"))
+        })?;
+    drop(metadata_guard);
+    if let Err(e) = runtime_usage.record_execution(runtime_id) {
+        eprintln!("{e}");
+    }
+    let runtime = match req.runtime_version {
+        Some(version) if version != runtime.version => load_version(runtime_id, runtime, version)
+            .await
+            .map_err(|e| ApiError::Internal(anyhow!("Failed to load runtime version: {e}")))?
+            .ok_or_else(|| {
+                ApiError::bad_request(format!(
+                    "Runtime with id: {runtime_id} does not have version: {version}"
+                ))
+            })?,
+        _ => runtime,
+    };
+    // The request can override the defaults of the runtime up to the system limits
+    let compile_limits = with_defaults(&req.compile_limits, &runtime.limits.compile)
+        .get(&system_limits.compile)
+        .map_err(|e| ApiError::bad_request(format!("Invalid compile limits: {e}")))?;
+    let run_limits = with_defaults(&req.run_limits, &runtime.limits.run)
+        .get(&system_limits.run)
+        .map_err(|e| ApiError::bad_request(format!("Invalid run limits: {e}")))?;
 
-    let current_box_id = get_next_box_id(&box_id);
-    let mut execution_box = Isolate::init(current_box_id).await.map_err(|e| {
-        eprintln!("Failed to initialize sandbox: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })?;
+    let res = run_submission(
+        &box_id_allocator,
+        &cleaner,
+        runtime_id,
+        &runtime,
+        req.source_code,
+        req.input,
+        is_project,
+        &compile_limits,
+        &run_limits,
+    )
+    .await?;
+    Ok(Json(res).into_response())
+}
+
+/// Runs a submission in a new sandbox, `runtime` doesn't have to be the active version
+#[allow(clippy::too_many_arguments)]
+pub async fn run_submission(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: &Arc<Cleaner>,
+    runtime_id: u32,
+    runtime: &Runtime,
+    source_code: String,
+    input: Option<String>,
+    is_project: bool,
+    compile_limits: &MandatoryLimits,
+    run_limits: &MandatoryLimits,
+) -> Result<ExecutionResponse, ApiError> {
+    let current_box_id = box_id_allocator
+        .allocate()
+        .map_err(|e| anyhow!("Failed to allocate a box ID: {e}"))?;
+    let mut execution_box = Isolate::init(current_box_id, cleaner.clone())
+        .await
+        .map_err(|e| anyhow!("Failed to initialize sandbox: {e}"))?;
 
+    let res = run_stages(
+        &mut execution_box,
+        box_id_allocator,
+        cleaner,
+        runtime_id,
+        runtime,
+        source_code,
+        input,
+        is_project,
+        compile_limits,
+        run_limits,
+    )
+    .await;
+    execution_box.close().await;
+    res
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_stages(
+    execution_box: &mut Isolate,
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: &Arc<Cleaner>,
+    runtime_id: u32,
+    runtime: &Runtime,
+    mut source_code: String,
+    input: Option<String>,
+    is_project: bool,
+    compile_limits: &MandatoryLimits,
+    run_limits: &MandatoryLimits,
+) -> Result<ExecutionResponse, ApiError> {
     let initial_submission_dir = format!("{}/submission", execution_box.box_dir);
-    fs::create_dir(&initial_submission_dir).await.map_err(|e| {
-        eprintln!("Failed to create submission directory: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })?;
+    fs::create_dir(&initial_submission_dir)
+        .await
+        .map_err(|e| anyhow!("Failed to create submission directory: {e}"))?;
 
     if is_project {
-        let (req_ret, decoded_res) = task::spawn_blocking(move || {
-            let decoded = BASE64_STANDARD.decode(&req.source_code);
-            (req, decoded)
-        })
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to spawn blocking decoding task: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
+        let decoded_res = task::spawn_blocking(move || BASE64_STANDARD.decode(&source_code))
+            .await
+            .map_err(|e| anyhow!("Failed to spawn blocking decoding task: {e}"))?;
         // Errors returned from decoding should be safe to show in response
-        let decoded = decoded_res.map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                Json(Message {
-                    message: format!("Invalid base64: {e}"),
-                }),
-            )
-                .into_response()
-        })?;
-        req = req_ret;
+        let decoded =
+            decoded_res.map_err(|e| ApiError::bad_request(format!("Invalid base64: {e}")))?;
         fs::write(
             format!("{}/{}", initial_submission_dir, SOURCE_ZIP_NAME),
             &decoded,
         )
         .await
     } else {
-        req.source_code.add_new_line_if_none();
+        source_code.add_new_line_if_none();
         fs::write(
             format!("{}/{}", initial_submission_dir, runtime.source_file_name),
-            &req.source_code,
+            &source_code,
         )
         .await
     }
     .map_err(|e| {
-        eprintln!(
+        anyhow!(
             "Failed to write the source code in {}: {}",
-            execution_box.box_dir, e
-        );
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+            execution_box.box_dir,
+            e
+        )
     })?;
 
     let extraction_result = if is_project {
         let res = execution_box
             .run(
                 &[],
-                &compile_limits,
+                compile_limits,
                 None,
                 "/box/submission",
                 None,
                 &["/bin/unzip", "-qq", SOURCE_ZIP_NAME],
             )
             .await
-            .map_err(|e| {
-                eprintln!("Failed to run isolate to unzip the source file: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
+            .map_err(|e| anyhow!("Failed to run isolate to unzip the source file: {e}"))?;
         if res.exit_code != Some(0) {
-            return Ok(Json(ExecutionResponse {
+            return Ok(ExecutionResponse {
                 extract: Some(res),
                 compile: None,
                 run: None,
-            })
-            .into_response());
+            });
         }
-        renew_box(&box_id, &mut execution_box).await.map_err(|e| {
-            eprintln!("Failed to renew box after extraction: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
+        renew_box(box_id_allocator, cleaner, execution_box)
+            .await
+            .map_err(|e| anyhow!("Failed to renew box after extraction: {e}"))?;
         Some(res)
     } else {
         None
     };
 
-    let runtime_dir = format!("{}/{}", RUNTIMES_DIR, req.runtime_id);
-    let mounts = ["/nix", &format!("/runtime={runtime_dir}")];
+    let runtime_dir = get_version_dir(runtime_id, runtime.version);
+    // Only the closure of the runtime is visible, not the rest of the store or the Nix database
+    let mut mounts = read_closure_mounts(&runtime_dir).await.map_err(|e| {
+        anyhow!("Failed to get the mounts of runtime with id: {runtime_id}\nError: {e}")
+    })?;
+    mounts.push(format!("/runtime={runtime_dir}"));
+    let mounts: Vec<&str> = mounts.iter().map(String::as_str).collect();
 
     let compile_result = if runtime.is_compiled {
         let res = execution_box
             .run(
                 &mounts,
-                &compile_limits,
+                compile_limits,
                 None,
                 "/box/submission",
-                Some(&format!("{runtime_dir}/env")),
+                Some(&runtime_dir),
                 &["/runtime/compile"],
             )
             .await
-            .map_err(|e| {
-                eprintln!("Failed to compile submission: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
+            .map_err(|e| anyhow!("Failed to compile submission: {e}"))?;
 
         if res.exit_code == Some(0) {
-            renew_box(&box_id, &mut execution_box).await.map_err(|e| {
-                eprintln!("Failed to renew box: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
+            renew_box(box_id_allocator, cleaner, execution_box)
+                .await
+                .map_err(|e| anyhow!("Failed to renew box: {e}"))?;
         } else {
-            return Ok(Json(ExecutionResponse {
+            return Ok(ExecutionResponse {
                 extract: extraction_result,
                 compile: Some(res),
                 run: None,
-            })
-            .into_response());
+            });
         }
         Some(res)
     } else {
         None
     };
 
-    let stdin = if let Some(mut s) = req.input {
+    let stdin = if let Some(mut s) = input {
         s.add_new_line_if_none();
         Some(s)
     } else {
@@ -256,23 +337,19 @@ pub async fn execute(
         execution_box
             .run(
                 &mounts,
-                &run_limits,
+                run_limits,
                 stdin.as_deref(),
                 "/box/submission",
-                Some(&format!("{runtime_dir}/env")),
+                Some(&runtime_dir),
                 &["/runtime/run"],
             )
             .await
-            .map_err(|e| {
-                eprintln!("Failed to run submission: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?,
+            .map_err(|e| anyhow!("Failed to run submission: {e}"))?,
     );
 
-    Ok(Json(ExecutionResponse {
+    Ok(ExecutionResponse {
         extract: extraction_result,
         compile: compile_result,
         run: run_result,
     })
-    .into_response())
 }
@@ -1,285 +1,815 @@
 use std::{
+    collections::HashSet,
     fs::Permissions,
-    os::unix::fs::PermissionsExt,
-    sync::{atomic::AtomicU64, Arc},
+    future::Future,
+    io,
+    os::unix::{fs::PermissionsExt, process::CommandExt},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use crate::{
-    api::{
-        common_functions::get_next_box_id,
-        common_responses::{Message, StaticMessage, INTERNAL_SERVER_ERROR_RESPONSE},
-    },
-    globals::{DB_PATH, RUNTIMES_DIR, TEMP_DIR},
+    api::{closures::import_closure_archive, errors::ApiError},
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    closure_mounts::write_closure_mounts,
+    globals::{DB_PATH, RUNTIMES_DIR, STAGING_DIR},
+    installation_jobs::{get_log_path, InstallationJob, InstallationJobs, STATUS_CANCELLED},
+    limits::{RuntimeLimits, SystemLimits},
+    nix::{self, NixSource, NixpkgsPin},
+    runtime_env::{parse_env_output, write_env, write_env_settings, Env, EnvSettings},
+    runtime_tests::{run_tests, write_tests, RuntimeTest},
+    runtime_versions::get_version_dir,
+    store_usage::write_closure_sizes,
     strings::NewLine,
     temp_dir::TempDir,
     transaction::Transaction,
-    types::{Metadata, Runtime, WholeSeconds},
+    types::{Aliases, Metadata, Runtime, WholeSeconds},
 };
+use anyhow::{anyhow, Error};
 use axum::{
     body::Body,
-    http::StatusCode,
+    extract::Path,
+    http::{header, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use futures_util::stream;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
-use tokio::{fs, process::Command, sync::RwLock, task};
+use tokio::{fs, io::AsyncReadExt, process::Command, sync::RwLock, task, time};
 
-#[derive(Deserialize)]
+const LOG_CHUNK_SIZE: usize = 8192;
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+pub const SCRIPT_HEADER: &str = "#!/bin/bash\n\n";
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct AddRuntimeRequest {
-    name: String,
-    nix_shell: String,
-    compile_script: String,
-    run_script: String,
-    source_file_name: String,
+    pub name: String,
+    #[serde(default)]
+    pub nix_shell: String,
+    /// Used instead of `nix_shell`, e.g. `github:owner/repo#devShells.x86_64-linux.default`
+    pub flake: Option<String>,
+    /// What `<nixpkgs>` resolves to in `nix_shell`
+    pub nixpkgs: Option<NixpkgsPin>,
+    pub compile_script: String,
+    pub run_script: String,
+    pub source_file_name: String,
+    #[serde(default)]
+    pub limits: RuntimeLimits,
+    #[serde(default)]
+    pub tests: Vec<RuntimeTest>,
+    #[serde(default)]
+    pub env_settings: EnvSettings,
+}
+
+/// Where the environment of a new runtime comes from
+pub enum EnvironmentSource {
+    /// Built by Nix from the definition of the runtime
+    Build,
+    /// Imported without network access from a closure archive extracted in the directory
+    Closure(TempDir),
+}
+
+impl AddRuntimeRequest {
+    pub fn nix_source(&self) -> NixSource {
+        NixSource {
+            flake: self.flake.clone(),
+            nixpkgs: self.nixpkgs.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct InstallationJobResponse {
+    pub id: u32,
 }
 
 #[derive(Serialize)]
 pub struct InstallationResponse {
-    stdout: String,
-    stderr: String,
+    pub stdout: String,
+    pub stderr: String,
 }
 
-async fn validate_request(req: &AddRuntimeRequest) -> Result<(), Response<Body>> {
+pub async fn validate_request(
+    req: &AddRuntimeRequest,
+    system_limits: &SystemLimits,
+) -> Result<(), ApiError> {
     let bad_request_message = if req.name.is_empty() {
-        "Name can't be empty"
-    } else if req.nix_shell.is_empty() {
-        "Nix shell can't be empty"
+        "Name can't be empty".to_string()
+    } else if req.nix_shell.is_empty() && req.flake.is_none() {
+        "Nix shell can't be empty".to_string()
+    } else if !req.nix_shell.is_empty() && req.flake.is_some() {
+        "Only one of nix_shell and flake can be specified".to_string()
+    } else if req.flake.is_some() && req.nixpkgs.is_some() {
+        "nixpkgs can't be pinned for a flake, its inputs are locked by the flake".to_string()
+    } else if req
+        .flake
+        .as_ref()
+        .is_some_and(|flake| flake.is_empty() || flake.starts_with('-'))
+    {
+        "Invalid flake reference".to_string()
+    } else if let Some(Err(e)) = req.nixpkgs.as_ref().map(NixpkgsPin::validate) {
+        e.to_string()
     } else if req.run_script.is_empty() {
-        "Run command can't be empty"
+        "Run command can't be empty".to_string()
     } else if req.source_file_name.is_empty() {
-        "Source file name can't be empty"
+        "Source file name can't be empty".to_string()
     } else if sanitize_filename::sanitize(&req.source_file_name) != req.source_file_name {
-        "Invalid source file name"
+        "Invalid source file name".to_string()
+    } else if let Err(e) = req.limits.get(system_limits) {
+        e.to_string()
+    } else if let Err(e) = req.env_settings.validate() {
+        e.to_string()
     } else {
-        ""
+        String::new()
     };
     if !bad_request_message.is_empty() {
-        Err((
-            StatusCode::BAD_REQUEST,
-            Json(Message {
-                message: bad_request_message.to_string(),
-            }),
-        )
-            .into_response())
+        Err(ApiError::bad_request(bad_request_message))
     } else {
         Ok(())
     }
 }
 
-const NIX_BIN_PATH: &str = "/home/envicutor/.nix-profile/bin";
+/// Validates the request and normalizes its scripts
+pub async fn prepare_request(
+    req: &mut AddRuntimeRequest,
+    system_limits: &SystemLimits,
+) -> Result<(), ApiError> {
+    validate_request(req, system_limits).await?;
+    req.nix_shell.add_new_line_if_none();
+    req.compile_script.add_new_line_if_none();
+    req.run_script.add_new_line_if_none();
+    Ok(())
+}
+
+pub async fn write_runtime_scripts(
+    runtime_dir: &str,
+    req: &AddRuntimeRequest,
+) -> Result<(), Error> {
+    if !req.compile_script.is_empty() {
+        let compile_script_path = format!("{runtime_dir}/compile");
+        crate::fs::write_file_and_set_permissions(
+            &compile_script_path,
+            &format!("{SCRIPT_HEADER}{}", req.compile_script),
+            Permissions::from_mode(0o755),
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to write compile script: {e}"))?;
+    }
+
+    let run_script_path = format!("{runtime_dir}/run");
+    crate::fs::write_file_and_set_permissions(
+        &run_script_path,
+        &format!("{SCRIPT_HEADER}{}", req.run_script),
+        Permissions::from_mode(0o755),
+    )
+    .await
+    .map_err(|e| anyhow!("Failed to write run script: {e}"))?;
+    Ok(())
+}
+
+pub async fn write_runtime_limits(runtime_dir: &str, limits: &RuntimeLimits) -> Result<(), Error> {
+    let limits_path = format!("{runtime_dir}/limits.json");
+    let limits = serde_json::to_string(limits)
+        .map_err(|e| anyhow!("Failed to serialize runtime limits: {e}"))?;
+    fs::write(&limits_path, limits)
+        .await
+        .map_err(|e| anyhow!("Failed to write {limits_path}\nError: {e}"))
+}
+
+/// Returns an empty string if the script doesn't exist
+pub async fn read_script(path: &str) -> Result<String, Error> {
+    match fs::read_to_string(path).await {
+        Ok(script) => Ok(script
+            .strip_prefix(SCRIPT_HEADER)
+            .unwrap_or(&script)
+            .to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(anyhow!("Failed to read {path}\nError: {e}")),
+    }
+}
+
+/// Reserves a runtime name for the duration of an installation or an edit,
+/// so different runtimes can be installed in parallel but the same one can't
+pub struct NameReservation {
+    name: String,
+    installations: Arc<Mutex<HashSet<String>>>,
+}
+
+impl NameReservation {
+    pub async fn new(
+        name: &str,
+        installations: &Arc<Mutex<HashSet<String>>>,
+        metadata_cache: &Arc<RwLock<Metadata>>,
+        aliases_cache: &Arc<RwLock<Aliases>>,
+    ) -> Result<Self, ApiError> {
+        // Held until the name is reserved since aliases can't be set to reserved names,
+        // taken before the metadata like when setting an alias
+        let aliases_guard = aliases_cache.read().await;
+        if aliases_guard.contains_key(name) {
+            return Err(ApiError::bad_request(
+                "An alias with this name already exists",
+            ));
+        }
+        let metadata_guard = metadata_cache.read().await;
+        if metadata_guard.values().any(|runtime| runtime.name == name) {
+            return Err(ApiError::bad_request(
+                "A runtime with this name already exists",
+            ));
+        }
+        Self::reserve(name, installations)?.ok_or_else(|| {
+            ApiError::conflict("A runtime with this name is already being installed")
+        })
+    }
+
+    /// Returns `None` if the name is already reserved, doesn't check if a runtime has the name
+    pub fn reserve(
+        name: &str,
+        installations: &Arc<Mutex<HashSet<String>>>,
+    ) -> Result<Option<Self>, Error> {
+        let mut installations_guard = installations
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock installations in progress: {e}"))?;
+        if !installations_guard.insert(name.to_string()) {
+            return Ok(None);
+        }
+        Ok(Some(NameReservation {
+            name: name.to_string(),
+            installations: installations.clone(),
+        }))
+    }
+}
+
+impl Drop for NameReservation {
+    fn drop(&mut self) {
+        match self.installations.lock() {
+            Ok(mut installations) => {
+                installations.remove(&self.name);
+            }
+            Err(e) => eprintln!("Failed to lock installations in progress: {e}"),
+        }
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn install_runtime(
     installation_timeout: WholeSeconds,
-    box_id: Arc<AtomicU64>,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
     metadata_cache: Arc<RwLock<Metadata>>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    aliases_cache: Arc<RwLock<Aliases>>,
+    installation_jobs: Arc<InstallationJobs>,
     installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
     Json(mut req): Json<AddRuntimeRequest>,
 ) -> Result<Response<Body>, Response<Body>> {
-    let _permit = installation_lock.write().await;
-    validate_request(&req).await?;
-    req.nix_shell.add_new_line_if_none();
-    req.compile_script.add_new_line_if_none();
-    req.run_script.add_new_line_if_none();
-
-    let current_box_id = get_next_box_id(&box_id);
+    prepare_request(&mut req, &system_limits).await?;
+    let name_reservation =
+        NameReservation::new(&req.name, &installations, &metadata_cache, &aliases_cache).await?;
+    let id = start_installation(
+        installation_timeout,
+        box_id_allocator,
+        cleaner,
+        metadata_cache,
+        installation_jobs,
+        installation_lock,
+        system_limits,
+        name_reservation,
+        req,
+        Vec::new(),
+        EnvironmentSource::Build,
+    )
+    .await?;
+    Ok((StatusCode::ACCEPTED, Json(InstallationJobResponse { id })).into_response())
+}
 
-    let workdir = TempDir::new(format!("{TEMP_DIR}/{current_box_id}-submission"))
+/// Runs the installation of an already validated request in the background,
+/// returns the ID of the installation job
+#[allow(clippy::too_many_arguments)]
+pub async fn start_installation(
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    installation_jobs: Arc<InstallationJobs>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+    name_reservation: NameReservation,
+    req: AddRuntimeRequest,
+    tags: Vec<String>,
+    environment: EnvironmentSource,
+) -> Result<u32, ApiError> {
+    let job = installation_jobs
+        .create(&req.name)
         .await
-        .map_err(|e| {
-            eprintln!("Failed to create workdir: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
+        .map_err(|e| anyhow!("Failed to create installation job: {e}"))?;
+    let id = job.id;
 
-    let nix_shell_path = format!("{}/shell.nix", workdir.path);
-    fs::write(&nix_shell_path, &req.nix_shell)
-        .await
-        .map_err(|e| {
-            eprintln!("Could not write nix shell file: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
-
-    let metadata_guard = metadata_cache.read().await;
-    if metadata_guard
-        .values()
-        .any(|runtime| runtime.name == req.name)
-    {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            Json(StaticMessage {
-                message: "A runtime with this name already exists",
-            }),
+    tokio::spawn(async move {
+        let _name_reservation = name_reservation;
+        // Shared with executions and other installations, only Nix updates need to exclude installations
+        let permit = installation_lock.read().await;
+        // Garbage collection must not remove the store paths of the shell before they are rooted
+        let gc_guard = nix::hold_off_garbage_collection().await;
+        let res = run_installation(
+            &job,
+            installation_timeout,
+            box_id_allocator,
+            cleaner,
+            &metadata_cache,
+            &system_limits,
+            req,
+            tags,
+            &environment,
         )
-            .into_response());
+        .await;
+        drop(gc_guard);
+        drop(permit);
+        // The store paths are rooted by now, so uploading them doesn't hold off Nix updates
+        // or garbage collection
+        if let Ok(runtime_id) = res {
+            upload_environment(&job, runtime_id, installation_timeout).await;
+        }
+        if let EnvironmentSource::Closure(archive_dir) = environment {
+            archive_dir.close().await;
+        }
+        installation_jobs.finish(&job, res.map(Some)).await;
+    });
+    Ok(id)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_installation(
+    job: &InstallationJob,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: &Arc<RwLock<Metadata>>,
+    system_limits: &SystemLimits,
+    req: AddRuntimeRequest,
+    tags: Vec<String>,
+    environment: &EnvironmentSource,
+) -> Result<u32, Error> {
+    let current_box_id = box_id_allocator.allocate()?;
+
+    // The runtime is built in a staging directory on the same filesystem as the runtimes
+    // so it can be atomically moved into place once it's ready
+    let staging_dir = TempDir::new(
+        format!("{STAGING_DIR}/{}", current_box_id.get()),
+        Some(current_box_id),
+        cleaner.clone(),
+    )
+    .await
+    .map_err(|e| anyhow!("Failed to create staging directory: {e}"))?;
+
+    let res = install_in_staging_dir(
+        job,
+        &staging_dir,
+        installation_timeout,
+        &box_id_allocator,
+        cleaner,
+        metadata_cache,
+        system_limits,
+        req,
+        tags,
+        environment,
+    )
+    .await;
+    staging_dir.close().await;
+    res
+}
+
+async fn kill_process_group(pgid: u32) {
+    let kill_res = Command::new("/bin/kill")
+        .args(["-SIGKILL", "--", &format!("-{pgid}")])
+        .output()
+        .await;
+    if let Err(e) = kill_res {
+        eprintln!("Could not kill process group {pgid}. Maybe it has already exited: {e}");
     }
-    drop(metadata_guard);
+}
 
-    let mut cmd = Command::new("env");
-    cmd.arg("-i")
-        .arg("PATH=/bin")
-        .arg(format!("{NIX_BIN_PATH}/nix-shell"))
-        .args(["--timeout".to_string(), installation_timeout.to_string()])
-        .arg(nix_shell_path)
-        .args(["--run", "/bin/bash -c env"]);
-    let cmd_res = cmd.output().await.map_err(|e| {
-        eprintln!("Failed to run nix-shell: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-    })?;
-    let stdout = String::from_utf8_lossy(&cmd_res.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&cmd_res.stderr).to_string();
-    let success = cmd_res.status.success();
-
-    if success {
-        let runtime_name = req.name.clone();
-        let source_file_name = req.source_file_name.clone();
-
-        let (runtime_id, mut trx) = task::spawn_blocking(move || {
-            let connection = Connection::open(DB_PATH).map_err(|e| {
-                eprintln!("Failed to open SQLite connection: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
+/// Stops a step of an installation as soon as it's cancelled, the Nix commands it runs are spawned
+/// with `kill_on_drop` and the boxes of smoke tests are cleaned up when they are dropped
+async fn unless_cancelled<T>(
+    job: &InstallationJob,
+    step: &str,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    tokio::select! {
+        res = future => res,
+        _ = job.cancelled() => Err(anyhow!("Installation was cancelled while {step}")),
+    }
+}
 
-            connection
-                .execute(
-                    "INSERT INTO runtime (name, source_file_name) VALUES (?, ?)",
-                    (&runtime_name, &source_file_name),
-                )
-                .map_err(|e| {
-                    eprintln!("Failed to execute statement: {e}");
-                    INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-                })?;
+/// Runs `nix-shell`, or `nix develop` for flakes, and returns the environment it sets up,
+/// its stderr is streamed to the job's log.
+/// It runs in its own process group so that cancelling the job also kills its builders
+async fn get_nix_shell_env(
+    job: &InstallationJob,
+    runtime_dir: &str,
+    installation_timeout: WholeSeconds,
+) -> Result<Env, Error> {
+    let mut cmd = nix::env_std_command(runtime_dir, installation_timeout).await?;
+    cmd.process_group(0);
+    let mut cmd = Command::from(cmd);
+    cmd.stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| anyhow!("Failed to run nix-shell: {e}"))?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Failed to get the stdout of nix-shell"))?;
+    let mut stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("Failed to get the stderr of nix-shell"))?;
+    let mut log = job.open_log().await?;
 
-            let trx = Transaction::init(
-                move |conn| {
-                    let res = conn.execute("DELETE FROM runtime WHERE name = ?", [&runtime_name]);
-                    if let Err(e) = res {
-                        eprintln!(
-                            "Failed to remove runtime with name: {runtime_name} during rollback\nError: {e}"
-                        );
-                    }
-                },
-            );
+    let mut env = Vec::new();
+    let output = async {
+        tokio::join!(
+            tokio::io::copy(&mut stderr, &mut log),
+            stdout.read_to_end(&mut env)
+        )
+    };
+    let (log_res, stdout_res) = tokio::select! {
+        res = output => res,
+        _ = job.cancelled() => {
+            if let Some(pgid) = child.id() {
+                kill_process_group(pgid).await;
+            }
+            let _ = child.wait().await;
+            return Err(anyhow!("Installation was cancelled while running nix-shell"));
+        }
+    };
+    log_res.map_err(|e| anyhow!("Failed to write the output of nix-shell to the log: {e}"))?;
+    stdout_res.map_err(|e| anyhow!("Failed to read the output of nix-shell: {e}"))?;
 
-            let row_id = connection
-                .query_row("SELECT last_insert_rowid()", (), |row| row.get(0))
-                .map_err(|e| {
-                    eprintln!("Failed to get last inserted row id: {e}");
-                    INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-                })?;
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| anyhow!("Failed to wait for nix-shell: {e}"))?;
+    if !status.success() {
+        return Err(anyhow!("nix-shell failed with {status}"));
+    }
+    parse_env_output(&env)
+}
 
-            Ok((row_id, trx))
-        })
+#[allow(clippy::too_many_arguments)]
+async fn install_in_staging_dir(
+    job: &InstallationJob,
+    staging_dir: &TempDir,
+    installation_timeout: WholeSeconds,
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: &Arc<RwLock<Metadata>>,
+    system_limits: &SystemLimits,
+    req: AddRuntimeRequest,
+    tags: Vec<String>,
+    environment: &EnvironmentSource,
+) -> Result<u32, Error> {
+    let nix_shell_path = format!("{}/shell.nix", staging_dir.path);
+    fs::write(&nix_shell_path, &req.nix_shell)
         .await
-        .map_err(|e| {
-            eprintln!("Failed to spawn blocking task: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })??;
+        .map_err(|e| anyhow!("Could not write nix shell file: {e}"))?;
 
-        let runtime_dir = format!("{RUNTIMES_DIR}/{runtime_id}");
-        crate::fs::create_dir_replacing_existing(&runtime_dir)
-            .await
+    nix::write_source(&staging_dir.path, &req.nix_source()).await?;
+    let (env, imported_store_paths) = match environment {
+        EnvironmentSource::Build => {
+            unless_cancelled(
+                job,
+                "locking the Nix source",
+                nix::lock_source(&staging_dir.path, installation_timeout),
+            )
+            .await?;
+            let env = get_nix_shell_env(job, &staging_dir.path, installation_timeout).await?;
+            (env, None)
+        }
+        EnvironmentSource::Closure(archive_dir) => {
+            let (env, store_paths) = unless_cancelled(
+                job,
+                "importing the Nix closure",
+                import_closure_archive(
+                    job,
+                    &archive_dir.path,
+                    &staging_dir.path,
+                    installation_timeout,
+                ),
+            )
+            .await?;
+            (env, Some(store_paths))
+        }
+    };
+    write_runtime_scripts(&staging_dir.path, &req).await?;
+    write_runtime_limits(&staging_dir.path, &req.limits).await?;
+    write_tests(&staging_dir.path, &req.tests).await?;
+    write_env_settings(&staging_dir.path, &req.env_settings).await?;
+    write_env(&staging_dir.path, &req.env_settings.apply(env)).await?;
+    let closure = write_closure_mounts(&staging_dir.path).await?;
+    let closure_sizes = write_closure_sizes(&staging_dir.path).await?;
+    job.log(&format!(
+        "The closure of the runtime has {} store path(s) taking {} byte(s)",
+        closure.len(),
+        closure_sizes.size
+    ))
+    .await;
+
+    let runtime_name = req.name.clone();
+    let source_file_name = req.source_file_name.clone();
+    let runtime_tags = tags.clone();
+    let cleaner_for_tests = cleaner.clone();
+
+    let (runtime_id, mut trx) = task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+
+        connection
+            .execute(
+                "INSERT INTO runtime (name, source_file_name) VALUES (?, ?)",
+                (&runtime_name, &source_file_name),
+            )
+            .map_err(|e| anyhow!("Failed to execute statement: {e}"))?;
+
+        let trx = Transaction::init(cleaner, move |conn| {
+            conn.execute(
+                "DELETE FROM runtime_tag WHERE runtime_id = (SELECT id FROM runtime WHERE name = ?)",
+                [&runtime_name],
+            )
             .map_err(|e| {
-                eprintln!("Failed to create: {runtime_dir}, error: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+                anyhow!(
+                    "Failed to remove tags of runtime with name: {runtime_name} during rollback\nError: {e}"
+                )
             })?;
-
-        let is_compiled = !req.compile_script.is_empty();
-        if is_compiled {
-            let compile_script_path = format!("{runtime_dir}/compile");
-            crate::fs::write_file_and_set_permissions(
-                &compile_script_path,
-                &format!("#!/bin/bash\n\n{}", req.compile_script),
-                Permissions::from_mode(0o755),
+            conn.execute(
+                "DELETE FROM runtime_version WHERE runtime_id = (SELECT id FROM runtime WHERE name = ?)",
+                [&runtime_name],
             )
-            .await
             .map_err(|e| {
-                eprintln!("Failed to write compile script: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+                anyhow!(
+                    "Failed to remove versions of runtime with name: {runtime_name} during rollback\nError: {e}"
+                )
             })?;
+            conn.execute("DELETE FROM runtime WHERE name = ?", [&runtime_name])
+                .map_err(|e| {
+                    anyhow!(
+                        "Failed to remove runtime with name: {runtime_name} during rollback\nError: {e}"
+                    )
+                })?;
+            Ok(())
+        });
+
+        let row_id: u32 = connection
+            .query_row("SELECT last_insert_rowid()", (), |row| row.get(0))
+            .map_err(|e| anyhow!("Failed to get last inserted row id: {e}"))?;
+        connection
+            .execute(
+                "INSERT INTO runtime_version (runtime_id, version, source_file_name, is_active) VALUES (?, 1, ?, TRUE)",
+                (row_id, &source_file_name),
+            )
+            .map_err(|e| anyhow!("Failed to execute statement: {e}"))?;
+        for tag in &runtime_tags {
+            connection
+                .execute(
+                    "INSERT INTO runtime_tag (runtime_id, tag) VALUES (?, ?)",
+                    (row_id, tag),
+                )
+                .map_err(|e| anyhow!("Failed to add tag: {tag}\nError: {e}"))?;
         }
 
-        let run_script_path = format!("{runtime_dir}/run");
-        crate::fs::write_file_and_set_permissions(
-            &run_script_path,
-            &format!("#!/bin/bash\n\n{}", req.run_script),
-            Permissions::from_mode(0o755),
-        )
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to write run script: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
+        Ok::<_, Error>((row_id, trx))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
 
-        let env_script_path = format!("{runtime_dir}/env");
-        crate::fs::write_file_and_set_permissions(
-            &env_script_path,
-            &stdout,
-            Permissions::from_mode(0o755),
-        )
-        .await
-        .map_err(|e| {
-            eprintln!("Failed to write env script: {e}");
-            INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-        })?;
+    // The runtime is not in the metadata cache yet, so nothing can be using its directory
+    let runtime_dir = format!("{RUNTIMES_DIR}/{runtime_id}");
+    let version_dir = get_version_dir(runtime_id, 1);
+    let move_res = match fs::create_dir(&runtime_dir).await {
+        Ok(()) => fs::rename(&staging_dir.path, &version_dir).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = move_res {
+        if let Err(e) = crate::fs::remove_dir_if_exists(&runtime_dir).await {
+            eprintln!("{e}");
+        }
+        trx.close().await;
+        return Err(anyhow!(
+            "Failed to move {} to {version_dir}: {e}",
+            staging_dir.path
+        ));
+    }
 
-        fs::write(&(format!("{runtime_dir}/shell.nix")), &req.nix_shell)
+    let gc_roots_res = unless_cancelled(job, "registering GC roots", async {
+        match &imported_store_paths {
+            None => nix::add_gc_roots(&version_dir, installation_timeout).await,
+            Some(store_paths) => {
+                nix::add_gc_roots_for_paths(&version_dir, store_paths, installation_timeout).await
+            }
+        }
+        .map_err(|e| anyhow!("Failed to register GC roots: {e}"))
+    })
+    .await;
+    let runtime = Runtime {
+        name: req.name,
+        is_compiled: !req.compile_script.is_empty(),
+        source_file_name: req.source_file_name,
+        version: 1,
+        tags,
+        limits: req.limits,
+    };
+    let res = match gc_roots_res {
+        Err(e) => Err(e),
+        Ok(()) => {
+            unless_cancelled(
+                job,
+                "running smoke tests",
+                run_smoke_tests(
+                    job,
+                    box_id_allocator,
+                    &cleaner_for_tests,
+                    runtime_id,
+                    &runtime,
+                    &req.tests,
+                    system_limits,
+                ),
+            )
             .await
-            .map_err(|e| {
-                eprintln!("Failed to write shell.nix: {e}");
-                INTERNAL_SERVER_ERROR_RESPONSE.into_response()
-            })?;
+        }
+    };
+    // Cancelling after this point has no effect, the runtime is committed
+    let res = match res {
+        Ok(()) if job.is_cancelled() => Err(anyhow!("Installation was cancelled")),
+        res => res,
+    };
+    if let Err(e) = res {
+        if let Err(e) = crate::fs::remove_dir_if_exists(&runtime_dir).await {
+            eprintln!("{e}");
+        }
+        trx.close().await;
+        return Err(e);
+    }
 
-        let mut metadata_guard = metadata_cache.write().await;
-        metadata_guard.insert(
-            runtime_id,
-            Runtime {
-                name: req.name,
-                is_compiled,
-                source_file_name: req.source_file_name,
-            },
-        );
-        drop(metadata_guard);
-        trx.commit();
+    let mut metadata_guard = metadata_cache.write().await;
+    metadata_guard.insert(runtime_id, runtime);
+    drop(metadata_guard);
+    trx.commit();
+    job.log(&format!("Installed runtime with id: {runtime_id}"))
+        .await;
+    Ok(runtime_id)
+}
+
+/// Sharing the environment is best effort, the runtime is already installed
+async fn upload_environment(job: &InstallationJob, runtime_id: u32, timeout: WholeSeconds) {
+    if let Err(e) = nix::upload_to_cache(&get_version_dir(runtime_id, 1), timeout).await {
+        job.log(&format!(
+            "Failed to upload the environment to the cache: {e}"
+        ))
+        .await;
     }
+}
 
-    let status_code = if success {
-        StatusCode::OK
-    } else {
-        StatusCode::BAD_REQUEST
-    };
-    Ok((status_code, Json(InstallationResponse { stdout, stderr })).into_response())
+/// Runs the tests of a runtime that isn't committed yet, every result is written to the job's log
+pub async fn run_smoke_tests(
+    job: &InstallationJob,
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: &Arc<Cleaner>,
+    runtime_id: u32,
+    runtime: &Runtime,
+    tests: &[RuntimeTest],
+    system_limits: &SystemLimits,
+) -> Result<(), Error> {
+    if tests.is_empty() {
+        return Ok(());
+    }
+    job.log(&format!("Running {} smoke test(s)", tests.len()))
+        .await;
+    let report = run_tests(
+        box_id_allocator,
+        cleaner,
+        runtime_id,
+        runtime,
+        tests,
+        system_limits,
+    )
+    .await?;
+    for (i, test) in report.tests.iter().enumerate() {
+        let status = if test.passed { "passed" } else { "failed" };
+        job.log(&format!("Smoke test {} {status}", i + 1)).await;
+        if !test.passed {
+            if let Some(run) = &test.result.run {
+                job.log(&format!(
+                    "Expected output: {:?}\nActual output: {:?}",
+                    test.expected_output, run.stdout
+                ))
+                .await;
+            }
+        }
+    }
+    if !report.passed {
+        return Err(anyhow!("Smoke tests failed"));
+    }
+    Ok(())
 }
 
-pub async fn update_nix(
-    nix_update_timeout: WholeSeconds,
-    installation_lock: Arc<RwLock<u8>>,
+pub async fn get_installation(
+    Path(id): Path<u32>,
+    installation_jobs: Arc<InstallationJobs>,
+) -> Result<Response<Body>, Response<Body>> {
+    let installation = installation_jobs
+        .get(id)
+        .await
+        .map_err(ApiError::Internal)?
+        .ok_or_else(installation_not_found)?;
+    Ok(Json(installation).into_response())
+}
+
+/// Cancels a running installation and waits until everything it created is cleaned up
+pub async fn cancel_installation(
+    Path(id): Path<u32>,
+    installation_jobs: Arc<InstallationJobs>,
 ) -> Result<Response<Body>, Response<Body>> {
-    let _permit = installation_lock.write().await;
-
-    let mut cmd = Command::new(format!("{NIX_BIN_PATH}/nix-env"));
-    cmd.arg("--install")
-        .args(["--file", "<nixpkgs>"])
-        .args(["--attr", "nix", "cacert"])
-        .args(["-I", "nixpkgs=channel:nixpkgs-unstable"])
-        .args(["--timeout".to_string(), nix_update_timeout.to_string()]);
-
-    let cmd_res = cmd.output().await.map_err(|e| {
-        eprintln!("Failed to get the output of the nix update command: {e}");
-        INTERNAL_SERVER_ERROR_RESPONSE.into_response()
+    let job = installation_jobs
+        .get_running(id)
+        .map_err(ApiError::Internal)?;
+    if let Some(job) = job {
+        job.cancel();
+        job.wait_until_finished().await;
+    }
+
+    let installation = installation_jobs
+        .get(id)
+        .await
+        .map_err(ApiError::Internal)?
+        .ok_or_else(installation_not_found)?;
+    if installation.status != STATUS_CANCELLED {
+        return Err(ApiError::conflict(format!(
+            "The installation has already finished with status: {}",
+            installation.status
+        ))
+        .into());
+    }
+    Ok(Json(installation).into_response())
+}
+
+fn installation_not_found() -> ApiError {
+    ApiError::not_found("Could not find the specified installation")
+}
+
+/// Streams the log of an installation, following it until the installation finishes
+pub async fn get_installation_logs(
+    Path(id): Path<u32>,
+    installation_jobs: Arc<InstallationJobs>,
+) -> Result<Response<Body>, Response<Body>> {
+    // The job is looked up before opening the log so that a job finishing in between is still
+    // followed until its end
+    let finished = installation_jobs
+        .get_running(id)
+        .map_err(ApiError::Internal)?
+        .map(|job| job.subscribe());
+    let log_path = get_log_path(id);
+    let log = fs::File::open(&log_path).await.map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            installation_not_found()
+        } else {
+            ApiError::Internal(anyhow!("Failed to open {log_path}\nError: {e}"))
+        }
     })?;
 
-    let status = if cmd_res.status.success() {
-        StatusCode::OK
-    } else {
-        StatusCode::INTERNAL_SERVER_ERROR
-    };
+    let stream = stream::unfold((log, finished), |(mut log, mut finished)| async move {
+        let mut buf = vec![0; LOG_CHUNK_SIZE];
+        loop {
+            match log.read(&mut buf).await {
+                Ok(0) => {
+                    let receiver = finished.as_mut()?;
+                    if *receiver.borrow_and_update() {
+                        // Read whatever was written before the job finished, then stop
+                        finished = None;
+                        continue;
+                    }
+                    let changed = time::timeout(LOG_POLL_INTERVAL, receiver.changed()).await;
+                    if let Ok(Err(_)) = changed {
+                        finished = None;
+                    }
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    return Some((Ok(buf), (log, finished)));
+                }
+                Err(e) => return Some((Err(e), (log, None))),
+            }
+        }
+    });
 
     Ok((
-        status,
-        Json(InstallationResponse {
-            stdout: String::from_utf8_lossy(&cmd_res.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&cmd_res.stderr).to_string(),
-        }),
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Body::from_stream(stream),
     )
         .into_response())
 }
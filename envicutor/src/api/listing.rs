@@ -1,25 +1,299 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
-use axum::{response::IntoResponse, Json};
-use serde::Serialize;
-use tokio::sync::RwLock;
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::RwLock, task};
 
-use crate::types::Metadata;
+use crate::{
+    api::{errors::ApiError, installation::read_script},
+    globals::DB_PATH,
+    limits::SystemLimits,
+    nix::{self, NixLock, NixpkgsPin},
+    runtime_env::{read_env_settings, read_submission_env, EnvSettings},
+    runtime_usage::{RuntimeUsage, UsageStats},
+    runtime_versions::get_version_dir,
+    store_usage::{get_runtime_store_usage, RuntimeStoreUsage},
+    types::{self, Metadata},
+};
 
 #[derive(Serialize)]
 pub struct Runtime {
     id: u32,
     name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_file_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_compiled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
 }
 
-pub async fn list_runtimes(metadata_cache: Arc<RwLock<Metadata>>) -> impl IntoResponse {
-    let mut runtimes: Vec<Runtime> = Vec::new();
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortBy {
+    #[default]
+    Id,
+    Name,
+}
+
+#[derive(Deserialize)]
+pub struct ListRuntimesQuery {
+    compiled: Option<bool>,
+    name_prefix: Option<String>,
+    tag: Option<String>,
+    #[serde(default)]
+    sort_by: SortBy,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+    #[serde(default)]
+    details: bool,
+}
+
+#[derive(Deserialize)]
+pub struct RuntimeDetailsQuery {
+    redact_env: bool,
+}
+
+#[derive(Serialize)]
+pub struct RuntimeDetails {
+    id: u32,
+    name: String,
+    version: u32,
+    source_file_name: String,
+    is_compiled: bool,
+    tags: Vec<String>,
+    created_at: String,
+    nix_shell: String,
+    flake: Option<String>,
+    nixpkgs: Option<NixpkgsPin>,
+    /// What the flake or the pinned nixpkgs resolved to, and the store paths of the environment
+    lock: Option<NixLock>,
+    compile_script: Option<String>,
+    run_script: String,
+    /// Values are `null` when redacted
+    env: BTreeMap<String, Option<String>>,
+    /// `null` when the env is redacted since extra variables can hold secrets
+    env_settings: Option<EnvSettings>,
+    limits: SystemLimits,
+    usage: UsageStats,
+    /// `null` if the runtime was deleted while being read or the store couldn't be measured.
+    /// Paths are only counted as shared with runtimes whose closures were already measured
+    store_usage: Option<RuntimeStoreUsage>,
+}
+
+fn matches_query(runtime: &types::Runtime, query: &ListRuntimesQuery) -> bool {
+    if let Some(compiled) = query.compiled {
+        if runtime.is_compiled != compiled {
+            return false;
+        }
+    }
+    if let Some(prefix) = &query.name_prefix {
+        if !runtime.name.starts_with(prefix) {
+            return false;
+        }
+    }
+    if let Some(tag) = &query.tag {
+        if !runtime.tags.contains(tag) {
+            return false;
+        }
+    }
+    true
+}
+
+/// FNV-1a, unlike `DefaultHasher` it doesn't change between Rust releases so ETags survive upgrades
+fn fnv1a(chunks: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Lists the runtimes matching the query, the total number of matches is in `X-Total-Count`
+/// and the list can be cached using its `ETag`
+pub async fn list_runtimes(
+    Query(query): Query<ListRuntimesQuery>,
+    headers: HeaderMap,
+    metadata_cache: Arc<RwLock<Metadata>>,
+) -> Result<Response<Body>, Response<Body>> {
     let metadata_guard = metadata_cache.read().await;
-    for (key, value) in metadata_guard.iter() {
-        runtimes.push(Runtime {
-            id: *key,
-            name: value.name.clone(),
-        });
+    let mut runtimes: Vec<Runtime> = metadata_guard
+        .iter()
+        .filter(|(_, runtime)| matches_query(runtime, &query))
+        .map(|(id, runtime)| Runtime {
+            id: *id,
+            name: runtime.name.clone(),
+            source_file_name: query.details.then(|| runtime.source_file_name.clone()),
+            is_compiled: query.details.then_some(runtime.is_compiled),
+            version: query.details.then_some(runtime.version),
+            tags: query.details.then(|| runtime.tags.clone()),
+        })
+        .collect();
+    drop(metadata_guard);
+    if query.sort_by == SortBy::Name {
+        runtimes.sort_by(|a, b| a.name.cmp(&b.name));
+    } else {
+        runtimes.sort_by_key(|runtime| runtime.id);
     }
-    Json(runtimes)
+
+    let total_count = runtimes.len();
+    let runtimes: Vec<Runtime> = runtimes
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect();
+    let body = serde_json::to_vec(&runtimes)
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to serialize runtimes: {e}")))?;
+    // The total count changes when runtimes outside the page are added or removed
+    let total_count = total_count.to_string();
+    let etag = format!("\"{:x}\"", fnv1a(&[total_count.as_bytes(), b"\n", &body]));
+    let total_count_header = (HeaderName::from_static("x-total-count"), total_count);
+
+    let is_cached = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim() == etag || value.trim() == "*");
+    if is_cached {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), total_count_header],
+        )
+            .into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::ETAG, etag),
+            total_count_header,
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Returns everything stored for the active version of a runtime
+pub async fn get_runtime(
+    Path(id): Path<u32>,
+    query: Option<Query<RuntimeDetailsQuery>>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    system_limits: SystemLimits,
+) -> Result<Response<Body>, Response<Body>> {
+    let redact_env = if let Some(query) = query {
+        query.redact_env
+    } else {
+        false
+    };
+    let runtime = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Could not find the specified runtime"))?;
+
+    let created_at = task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        connection
+            .query_row("SELECT created_at FROM runtime WHERE id = ?", [id], |row| {
+                row.get::<_, String>(0)
+            })
+            .map_err(|e| anyhow!("Failed to get runtime with id: {id}\nError: {e}"))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res)
+    .map_err(ApiError::Internal)?;
+
+    let runtime_dir = get_version_dir(id, runtime.version);
+    let nix_shell_path = format!("{runtime_dir}/shell.nix");
+    let nix_shell = fs::read_to_string(&nix_shell_path)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to read {nix_shell_path}\nError: {e}")))?;
+    let nix_source = nix::read_source(&runtime_dir)
+        .await
+        .map_err(ApiError::Internal)?;
+    let lock = nix::read_lock(&runtime_dir)
+        .await
+        .map_err(ApiError::Internal)?;
+    let compile_script = if runtime.is_compiled {
+        Some(
+            read_script(&format!("{runtime_dir}/compile"))
+                .await
+                .map_err(ApiError::Internal)?,
+        )
+    } else {
+        None
+    };
+    let run_script = read_script(&format!("{runtime_dir}/run"))
+        .await
+        .map_err(ApiError::Internal)?;
+    let env = read_submission_env(&runtime_dir)
+        .await
+        .map_err(ApiError::Internal)?
+        .into_iter()
+        .map(|(key, value)| (key, if redact_env { None } else { Some(value) }))
+        .collect();
+    let env_settings = if redact_env {
+        None
+    } else {
+        Some(
+            read_env_settings(&runtime_dir)
+                .await
+                .map_err(ApiError::Internal)?,
+        )
+    };
+
+    let limits = runtime.limits.get(&system_limits).map_err(|e| {
+        ApiError::Internal(anyhow!("Runtime with id: {id} has invalid limits: {e}"))
+    })?;
+
+    let usage = runtime_usage.stats(id).map_err(ApiError::Internal)?;
+
+    // The rest of the details are still useful when the store can't be measured
+    let store_usage = {
+        let metadata = metadata_cache.read().await.clone();
+        get_runtime_store_usage(&metadata, id)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to get the store usage of runtime with id: {id}\nError: {e}");
+                None
+            })
+    };
+
+    Ok(Json(RuntimeDetails {
+        id,
+        name: runtime.name,
+        version: runtime.version,
+        source_file_name: runtime.source_file_name,
+        is_compiled: runtime.is_compiled,
+        tags: runtime.tags,
+        created_at,
+        nix_shell,
+        flake: nix_source.flake,
+        nixpkgs: nix_source.nixpkgs,
+        lock,
+        compile_script,
+        run_script,
+        env,
+        env_settings,
+        limits,
+        usage,
+        store_usage,
+    })
+    .into_response())
 }
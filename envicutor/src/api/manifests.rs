@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::Query,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+
+use crate::{
+    api::errors::ApiError,
+    manifests::{ManifestSync, Plan, SyncReport},
+};
+
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    prune: bool,
+}
+
+async fn get_plan(
+    query: Option<Query<SyncQuery>>,
+    manifest_sync: &Option<Arc<ManifestSync>>,
+) -> Result<(&Arc<ManifestSync>, Plan), ApiError> {
+    let manifest_sync = manifest_sync.as_ref().ok_or_else(|| {
+        ApiError::not_found("Manifests are not configured, MANIFESTS_DIR is not set")
+    })?;
+    let prune = if let Some(query) = query {
+        query.prune
+    } else {
+        false
+    };
+    let plan = manifest_sync
+        .plan(prune)
+        .await
+        .map_err(|e| anyhow!("Failed to plan the sync of the manifests: {e}"))?;
+    Ok((manifest_sync, plan))
+}
+
+/// Reports what a sync would do without applying it
+pub async fn get_manifests_plan(
+    query: Option<Query<SyncQuery>>,
+    manifest_sync: Option<Arc<ManifestSync>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let (_, plan) = get_plan(query, &manifest_sync).await?;
+    Ok(Json(plan.summary).into_response())
+}
+
+/// Installs, updates and optionally prunes runtimes to match the manifests,
+/// nothing is applied if a manifest is invalid
+pub async fn sync_manifests(
+    query: Option<Query<SyncQuery>>,
+    manifest_sync: Option<Arc<ManifestSync>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let (manifest_sync, plan) = get_plan(query, &manifest_sync).await?;
+    if !plan.summary.invalid.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(SyncReport {
+                plan: plan.summary,
+                results: Vec::new(),
+            }),
+        )
+            .into_response());
+    }
+    Ok(Json(manifest_sync.apply(plan).await).into_response())
+}
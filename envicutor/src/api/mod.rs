@@ -3,4 +3,16 @@ pub mod listing;
 pub mod deletion;
 pub mod common_responses;
 pub mod execution;
-pub mod common_functions;
+pub mod cleanup;
+pub mod reconciliation;
+pub mod editing;
+pub mod versions;
+pub mod aliases;
+pub mod tags;
+pub mod bundles;
+pub mod manifests;
+pub mod testing;
+pub mod closures;
+pub mod updates;
+pub mod store;
+pub mod errors;
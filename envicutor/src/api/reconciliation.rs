@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use crate::{
+    api::errors::ApiError, box_id::BoxIdAllocator, reconciliation::reconcile,
+    runtime_usage::RuntimeUsage,
+};
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+    Json,
+};
+
+pub async fn get_reconciliation_report(
+    box_id_allocator: Arc<BoxIdAllocator>,
+    runtime_usage: Arc<RuntimeUsage>,
+) -> Result<Response<Body>, Response<Body>> {
+    let report = reconcile(&box_id_allocator, &runtime_usage, true)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to get reconciliation report: {e}")))?;
+    Ok(Json(report).into_response())
+}
@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tokio::sync::RwLock;
+
+use crate::{api::errors::ApiError, store_usage::get_store_usage, types::Metadata};
+
+/// Reports how much of the store each runtime uses, which paths they share and the size of the store
+pub async fn get_store_usage_report(
+    metadata_cache: Arc<RwLock<Metadata>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let metadata = metadata_cache.read().await.clone();
+    let usage = get_store_usage(&metadata)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to get the store usage: {e}")))?;
+    Ok(Json(usage).into_response())
+}
@@ -0,0 +1,112 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::Path,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::Connection;
+use serde::Deserialize;
+use tokio::{sync::RwLock, task};
+
+use crate::{
+    api::{errors::ApiError, installation::NameReservation},
+    globals::DB_PATH,
+    runtime_usage::RuntimeUsage,
+    types::Metadata,
+};
+
+#[derive(Deserialize)]
+pub struct SetTagsRequest {
+    pub tags: Vec<String>,
+}
+
+/// Validates the tags and sorts them without duplicates
+pub fn prepare_tags(tags: &mut Vec<String>) -> Result<(), ApiError> {
+    if tags.iter().any(|tag| tag.is_empty()) {
+        return Err(ApiError::bad_request("Tags can't be empty"));
+    }
+    tags.sort();
+    tags.dedup();
+    Ok(())
+}
+
+/// Replaces the tags of a runtime, tags aren't versioned so edits and rollbacks keep them
+pub async fn set_runtime_tags(
+    Path(id): Path<u32>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    Json(req): Json<SetTagsRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    set_tags(
+        id,
+        req.tags,
+        &metadata_cache,
+        &runtime_usage,
+        &installations,
+    )
+    .await?;
+    Ok(().into_response())
+}
+
+pub async fn set_tags(
+    id: u32,
+    mut tags: Vec<String>,
+    metadata_cache: &Arc<RwLock<Metadata>>,
+    runtime_usage: &Arc<RuntimeUsage>,
+    installations: &Arc<Mutex<HashSet<String>>>,
+) -> Result<(), ApiError> {
+    prepare_tags(&mut tags)?;
+
+    // Held so the runtime can't be deleted while its tags are being set
+    let _lease = runtime_usage
+        .acquire(id)
+        .map_err(|e| anyhow!("Failed to acquire runtime lease: {e}"))?
+        .ok_or_else(|| ApiError::conflict(format!("Runtime with id: {id} is being deleted")))?;
+    let runtime_name = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .map(|runtime| runtime.name.clone())
+        .ok_or_else(|| ApiError::not_found("Could not find the specified runtime"))?;
+    // An edit finishing in the meantime would overwrite the cached tags with the old ones
+    let _name_reservation = NameReservation::reserve(&runtime_name, installations)?
+        .ok_or_else(|| ApiError::conflict("The runtime is already being edited"))?;
+
+    let tags_clone = tags.clone();
+    task::spawn_blocking(move || {
+        let mut connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        let trx = connection
+            .transaction()
+            .map_err(|e| anyhow!("Failed to start SQLite transaction: {e}"))?;
+        trx.execute("DELETE FROM runtime_tag WHERE runtime_id = ?", [id])
+            .map_err(|e| anyhow!("Failed to delete tags of runtime with id: {id}\nError: {e}"))?;
+        for tag in &tags_clone {
+            trx.execute(
+                "INSERT INTO runtime_tag (runtime_id, tag) VALUES (?, ?)",
+                (id, tag),
+            )
+            .map_err(|e| {
+                anyhow!("Failed to add tag: {tag} to runtime with id: {id}\nError: {e}")
+            })?;
+        }
+        trx.commit()
+            .map_err(|e| anyhow!("Failed to commit SQLite transaction: {e}"))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+
+    let mut metadata_guard = metadata_cache.write().await;
+    if let Some(runtime) = metadata_guard.get_mut(&id) {
+        runtime.tags = tags;
+    }
+    drop(metadata_guard);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use tokio::sync::{RwLock, Semaphore};
+
+use crate::{
+    api::errors::ApiError,
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    limits::SystemLimits,
+    runtime_tests::{read_tests, run_tests},
+    runtime_usage::RuntimeUsage,
+    runtime_versions::{get_version_dir, load_version},
+    types::Metadata,
+};
+
+#[derive(Deserialize)]
+pub struct TestQuery {
+    version: u32,
+}
+
+/// Runs the smoke tests of the active version of a runtime, or of the given version
+#[allow(clippy::too_many_arguments)]
+pub async fn test_runtime(
+    Path(id): Path<u32>,
+    query: Option<Query<TestQuery>>,
+    semaphore: Arc<Semaphore>,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    cleaner: Arc<Cleaner>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installation_lock: Arc<RwLock<u8>>,
+    system_limits: SystemLimits,
+) -> Result<Response<Body>, Response<Body>> {
+    let _installation_guard = installation_lock.read().await;
+    let _permit = semaphore
+        .acquire()
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire execution semaphore: {e}")))?;
+    let _lease = runtime_usage
+        .acquire(id)
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire runtime lease: {e}")))?
+        .ok_or_else(|| ApiError::conflict(format!("Runtime with id: {id} is being deleted")))?;
+    let runtime = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Could not find the specified runtime"))?;
+    let runtime = match query {
+        Some(Query(TestQuery { version })) if version != runtime.version => {
+            load_version(id, runtime, version)
+                .await
+                .map_err(|e| ApiError::Internal(anyhow!("Failed to load runtime version: {e}")))?
+                .ok_or_else(|| {
+                    ApiError::not_found(format!(
+                        "Runtime with id: {id} does not have version: {version}"
+                    ))
+                })?
+        }
+        _ => runtime,
+    };
+
+    let tests = read_tests(&get_version_dir(id, runtime.version))
+        .await
+        .map_err(ApiError::Internal)?;
+    let report = run_tests(
+        &box_id_allocator,
+        &cleaner,
+        id,
+        &runtime,
+        &tests,
+        &system_limits,
+    )
+    .await
+    .map_err(|e| {
+        ApiError::Internal(anyhow!(
+            "Failed to run the tests of runtime with id: {id}\nError: {e}"
+        ))
+    })?;
+    Ok(Json(report).into_response())
+}
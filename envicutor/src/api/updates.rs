@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use axum::{
+    body::{Body, Bytes},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::RwLock, task};
+
+use crate::{
+    api::errors::ApiError,
+    globals::DB_PATH,
+    nix::{self, Generation},
+    types::WholeSeconds,
+};
+
+const DEFAULT_CHANNEL: &str = "nixpkgs-unstable";
+
+#[derive(Deserialize)]
+pub struct UpdateRequest {
+    channel: Option<String>,
+    revision: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct NixUpdateResponse {
+    stdout: String,
+    stderr: String,
+    generation_before: Option<u32>,
+    generation_after: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct NixUpdateRecord {
+    id: u32,
+    operation: String,
+    nixpkgs: Option<String>,
+    generation_before: Option<u32>,
+    generation_after: Option<u32>,
+    succeeded: bool,
+    created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct NixStatus {
+    version: String,
+    generation: Option<u32>,
+    generations: Vec<Generation>,
+    last_update: Option<NixUpdateRecord>,
+}
+
+/// Returns what `<nixpkgs>` should resolve to while updating.
+/// Only an empty body means the default channel, a body that isn't a valid request is rejected
+fn get_nixpkgs(body: Bytes) -> Result<String, ApiError> {
+    let (channel, revision) = if body.is_empty() {
+        (None, None)
+    } else {
+        let Json(req) = Json::<UpdateRequest>::from_bytes(&body)
+            .map_err(|e| ApiError::bad_request(e.body_text()))?;
+        (req.channel, req.revision)
+    };
+    match (channel, revision) {
+        (Some(_), Some(_)) => Err(ApiError::bad_request(
+            "Only one of channel and revision can be specified",
+        )),
+        (Some(channel), None) => {
+            if channel.is_empty()
+                || !channel
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+            {
+                return Err(ApiError::bad_request("Invalid channel"));
+            }
+            Ok(format!("channel:{channel}"))
+        }
+        (None, Some(revision)) => {
+            if revision.len() != 40 || !revision.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(ApiError::bad_request(
+                    "Invalid revision, expected a full commit hash",
+                ));
+            }
+            Ok(format!(
+                "https://github.com/NixOS/nixpkgs/archive/{revision}.tar.gz"
+            ))
+        }
+        (None, None) => Ok(format!("channel:{DEFAULT_CHANNEL}")),
+    }
+}
+
+/// Failing to record an operation doesn't fail it, it has already happened
+async fn record_operation(
+    operation: &'static str,
+    nixpkgs: Option<String>,
+    generation_before: Option<u32>,
+    generation_after: Option<u32>,
+    succeeded: bool,
+) {
+    let res = task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        connection
+            .execute(
+                "INSERT INTO nix_update (operation, nixpkgs, generation_before, generation_after, succeeded) VALUES (?, ?, ?, ?, ?)",
+                (operation, &nixpkgs, generation_before, generation_after, succeeded),
+            )
+            .map_err(|e| anyhow!("Failed to record Nix {operation}: {e}"))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res);
+    if let Err(e) = res {
+        eprintln!("{e}");
+    }
+}
+
+/// Upgrades Nix from a channel or a nixpkgs revision, `nixpkgs-unstable` by default.
+/// The generations of the profile before and after are returned and recorded
+pub async fn update_nix(
+    nix_update_timeout: WholeSeconds,
+    installation_lock: Arc<RwLock<u8>>,
+    body: Bytes,
+) -> Result<Response<Body>, Response<Body>> {
+    let nixpkgs = get_nixpkgs(body)?;
+    let _permit = installation_lock.write().await;
+    // The new profile generation isn't rooted until nix-env is done
+    let _gc_guard = nix::hold_off_garbage_collection().await;
+
+    let generation_before = nix::get_current_generation()
+        .await
+        .map_err(ApiError::Internal)?;
+    let mut cmd = nix::command("nix-env");
+    cmd.arg("--install")
+        .args(["--file", "<nixpkgs>"])
+        .args(["--attr", "nix", "cacert"])
+        .args(["-I", &format!("nixpkgs={nixpkgs}")])
+        .args(["--timeout".to_string(), nix_update_timeout.to_string()]);
+
+    let cmd_res = cmd.output().await.map_err(|e| {
+        ApiError::Internal(anyhow!(
+            "Failed to get the output of the nix update command: {e}"
+        ))
+    })?;
+    let generation_after = nix::get_current_generation()
+        .await
+        .map_err(ApiError::Internal)?;
+    record_operation(
+        "update",
+        Some(nixpkgs),
+        generation_before,
+        generation_after,
+        cmd_res.status.success(),
+    )
+    .await;
+
+    let status = if cmd_res.status.success() {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    Ok((
+        status,
+        Json(NixUpdateResponse {
+            stdout: String::from_utf8_lossy(&cmd_res.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&cmd_res.stderr).to_string(),
+            generation_before,
+            generation_after,
+        }),
+    )
+        .into_response())
+}
+
+/// Switches the profile back to the generation before the current one
+pub async fn rollback_nix(
+    installation_lock: Arc<RwLock<u8>>,
+) -> Result<Response<Body>, Response<Body>> {
+    let _permit = installation_lock.write().await;
+    // The new profile generation isn't rooted until nix-env is done
+    let _gc_guard = nix::hold_off_garbage_collection().await;
+
+    let generations = nix::list_generations().await.map_err(ApiError::Internal)?;
+    let generation_before = generations
+        .iter()
+        .find(|generation| generation.current)
+        .map(|generation| generation.generation);
+    let has_previous_generation = generations.iter().any(
+        |generation| matches!(generation_before, Some(current) if generation.generation < current),
+    );
+    if !has_previous_generation {
+        return Err(ApiError::conflict("There is no previous generation to roll back to").into());
+    }
+
+    let cmd_res = nix::command("nix-env")
+        .arg("--rollback")
+        .output()
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to run nix-env --rollback: {e}")))?;
+    let generation_after = nix::get_current_generation()
+        .await
+        .map_err(ApiError::Internal)?;
+    record_operation(
+        "rollback",
+        None,
+        generation_before,
+        generation_after,
+        cmd_res.status.success(),
+    )
+    .await;
+
+    let status = if cmd_res.status.success() {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok((
+        status,
+        Json(NixUpdateResponse {
+            stdout: String::from_utf8_lossy(&cmd_res.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&cmd_res.stderr).to_string(),
+            generation_before,
+            generation_after,
+        }),
+    )
+        .into_response())
+}
+
+fn get_last_update() -> Result<Option<NixUpdateRecord>, Error> {
+    let connection =
+        Connection::open(DB_PATH).map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+    connection
+        .query_row(
+            "SELECT id, operation, nixpkgs, generation_before, generation_after, succeeded, created_at
+            FROM nix_update ORDER BY id DESC LIMIT 1",
+            (),
+            |row| {
+                Ok(NixUpdateRecord {
+                    id: row.get(0)?,
+                    operation: row.get(1)?,
+                    nixpkgs: row.get(2)?,
+                    generation_before: row.get(3)?,
+                    generation_after: row.get(4)?,
+                    succeeded: row.get(5)?,
+                    created_at: row.get(6)?,
+                })
+            },
+        )
+        .optional()
+        .map_err(|e| anyhow!("Failed to get the last Nix update: {e}"))
+}
+
+/// Reports the installed Nix version, the generations of its profile and the last update or rollback
+pub async fn get_nix_status() -> Result<Response<Body>, Response<Body>> {
+    let version = nix::get_version().await.map_err(ApiError::Internal)?;
+    let generations = nix::list_generations().await.map_err(ApiError::Internal)?;
+    let last_update = task::spawn_blocking(get_last_update)
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+        .and_then(|res| res)
+        .map_err(ApiError::Internal)?;
+    Ok(Json(NixStatus {
+        version,
+        generation: generations
+            .iter()
+            .find(|generation| generation.current)
+            .map(|generation| generation.generation),
+        generations,
+        last_update,
+    })
+    .into_response())
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::Path,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rusqlite::Connection;
+use serde::Deserialize;
+use tokio::{sync::RwLock, task};
+
+use crate::{
+    api::{errors::ApiError, installation::NameReservation},
+    globals::DB_PATH,
+    runtime_usage::RuntimeUsage,
+    runtime_versions::{activate_version, list_versions, load_version},
+    types::Metadata,
+};
+
+#[derive(Deserialize)]
+pub struct RollbackRequest {
+    version: u32,
+}
+
+fn runtime_not_found() -> ApiError {
+    ApiError::not_found("Could not find the specified runtime")
+}
+
+pub async fn list_runtime_versions(
+    Path(id): Path<u32>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+) -> Result<Response<Body>, Response<Body>> {
+    if !metadata_cache.read().await.contains_key(&id) {
+        return Err(runtime_not_found().into());
+    }
+    let versions = task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        list_versions(&connection, id)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res)
+    .map_err(ApiError::Internal)?;
+    Ok(Json(versions).into_response())
+}
+
+/// Makes a previous version of a runtime the active one, new executions use it right away.
+/// The name isn't versioned so the runtime keeps its current name, a rename is undone with an edit
+pub async fn rollback_runtime(
+    Path(id): Path<u32>,
+    metadata_cache: Arc<RwLock<Metadata>>,
+    runtime_usage: Arc<RuntimeUsage>,
+    installations: Arc<Mutex<HashSet<String>>>,
+    Json(req): Json<RollbackRequest>,
+) -> Result<Response<Body>, Response<Body>> {
+    // Held so the runtime can't be deleted while it's being rolled back
+    let _lease = runtime_usage
+        .acquire(id)
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to acquire runtime lease: {e}")))?
+        .ok_or_else(|| ApiError::conflict(format!("Runtime with id: {id} is being deleted")))?;
+    let current_runtime = metadata_cache
+        .read()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(runtime_not_found)?;
+    // Shares the reservation with edits so a rollback can't race with a new version
+    let _name_reservation = NameReservation::reserve(&current_runtime.name, &installations)
+        .map_err(ApiError::Internal)?
+        .ok_or_else(|| ApiError::conflict("The runtime is already being edited"))?;
+
+    let version = req.version;
+    let runtime = load_version(id, current_runtime, version)
+        .await
+        .map_err(|e| ApiError::Internal(anyhow!("Failed to load runtime version: {e}")))?
+        .ok_or_else(|| {
+            ApiError::not_found(format!(
+                "Runtime with id: {id} does not have version: {version}"
+            ))
+        })?;
+
+    task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        activate_version(&connection, id, version)
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+    .and_then(|res| res)
+    .map_err(ApiError::Internal)?;
+
+    let mut metadata_guard = metadata_cache.write().await;
+    metadata_guard.insert(id, runtime);
+    drop(metadata_guard);
+    Ok(().into_response())
+}
@@ -0,0 +1,88 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error};
+
+use crate::globals::MAX_BOX_ID;
+
+struct AllocatorState {
+    next: u64,
+    in_use: HashSet<u64>,
+}
+
+pub struct BoxIdAllocator {
+    state: Mutex<AllocatorState>,
+}
+
+/// A box ID reserved from a `BoxIdAllocator`, it is returned to the allocator when dropped.
+/// Holders must only drop it after everything associated with the box has been cleaned up.
+pub struct BoxId {
+    id: u64,
+    allocator: Arc<BoxIdAllocator>,
+}
+
+impl BoxId {
+    pub fn get(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for BoxId {
+    fn drop(&mut self) {
+        self.allocator.release(self.id);
+    }
+}
+
+impl BoxIdAllocator {
+    pub fn new() -> Arc<Self> {
+        Arc::new(BoxIdAllocator {
+            state: Mutex::new(AllocatorState {
+                next: 0,
+                in_use: HashSet::new(),
+            }),
+        })
+    }
+
+    pub fn allocate(self: &Arc<Self>) -> Result<BoxId, Error> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock box ID allocator: {e}"))?;
+        // Start searching after the last allocated ID so recently released boxes are not
+        // immediately reused
+        for offset in 0..MAX_BOX_ID {
+            let id = (state.next + offset) % MAX_BOX_ID;
+            if state.in_use.insert(id) {
+                state.next = (id + 1) % MAX_BOX_ID;
+                return Ok(BoxId {
+                    id,
+                    allocator: self.clone(),
+                });
+            }
+        }
+        Err(anyhow!("All {MAX_BOX_ID} box IDs are in use"))
+    }
+
+    /// Reserves a specific box ID, returns `None` if it is already in use
+    pub fn try_reserve(self: &Arc<Self>, id: u64) -> Option<BoxId> {
+        let mut state = self.state.lock().ok()?;
+        if id >= MAX_BOX_ID || !state.in_use.insert(id) {
+            return None;
+        }
+        Some(BoxId {
+            id,
+            allocator: self.clone(),
+        })
+    }
+
+    fn release(&self, id: u64) {
+        match self.state.lock() {
+            Ok(mut state) => {
+                state.in_use.remove(&id);
+            }
+            Err(e) => eprintln!("Failed to release box ID {id}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio::{process::Command, runtime::Handle, sync::Notify, task, time};
+
+use crate::{
+    box_id::BoxId,
+    globals::{DB_PATH, ISOLATE_PATH},
+};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// With the delay doubling after every attempt, a cleanup is given up on after about 20 minutes
+const MAX_ATTEMPTS: u64 = 8;
+
+pub type RollbackFn = Arc<dyn Fn(&Connection) -> Result<(), Error> + Send + Sync>;
+
+pub enum CleanupTask {
+    Box {
+        box_id: BoxId,
+        metadata_file_path: String,
+        run_pid: Option<u32>,
+    },
+    Directory {
+        path: String,
+        box_id_guard: Option<BoxId>,
+    },
+    Rollback {
+        rollback_fn: RollbackFn,
+    },
+}
+
+impl CleanupTask {
+    fn describe(&self) -> String {
+        match self {
+            CleanupTask::Box { box_id, .. } => format!("box {}", box_id.get()),
+            CleanupTask::Directory { path, .. } => format!("directory {path}"),
+            CleanupTask::Rollback { .. } => "transaction rollback".to_string(),
+        }
+    }
+
+    async fn run(&mut self) -> Result<(), Error> {
+        match self {
+            CleanupTask::Box {
+                box_id,
+                metadata_file_path,
+                run_pid,
+            } => {
+                if let Some(pid) = run_pid.take() {
+                    let kill_res = Command::new("/bin/kill")
+                        .arg("-SIGABRT")
+                        .arg(pid.to_string())
+                        .output()
+                        .await;
+                    if let Err(e) = kill_res {
+                        eprintln!(
+                            "Could not kill `isolate --run` process. Maybe it has already exited: {e}"
+                        );
+                    }
+                    time::sleep(Duration::from_millis(50)).await;
+                }
+                cleanup_box(box_id.get(), metadata_file_path).await
+            }
+            CleanupTask::Directory { path, .. } => crate::fs::remove_dir_if_exists(path).await,
+            CleanupTask::Rollback { rollback_fn } => {
+                let rollback_fn = rollback_fn.clone();
+                task::spawn_blocking(move || {
+                    let connection = Connection::open(DB_PATH)
+                        .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+                    rollback_fn(&connection)
+                })
+                .await
+                .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+            }
+        }
+    }
+}
+
+pub async fn cleanup_box(box_id: u64, metadata_file_path: &str) -> Result<(), Error> {
+    let res = Command::new(ISOLATE_PATH)
+        .args(["--cleanup", "--cg", &format!("-b{}", box_id)])
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run `isolate --cleanup`\nError: {e}"))?;
+    if !res.status.success() {
+        return Err(anyhow!(
+            "`isolate --cleanup` failed with\nstderr: {}\nstdout: {}",
+            String::from_utf8_lossy(&res.stderr),
+            String::from_utf8_lossy(&res.stdout)
+        ));
+    }
+    crate::fs::remove_file_if_exists(metadata_file_path).await
+}
+
+struct QueuedTask {
+    task: CleanupTask,
+    attempts: u64,
+    retry_at: Instant,
+}
+
+impl QueuedTask {
+    fn new(task: CleanupTask) -> Self {
+        QueuedTask {
+            task,
+            attempts: 0,
+            retry_at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CleanupMetrics {
+    pub in_progress: usize,
+    pub queued_for_retry: usize,
+    pub succeeded: u64,
+    pub failed_attempts: u64,
+    pub succeeded_after_retry: u64,
+    pub given_up: u64,
+}
+
+/// Runs the cleanup of boxes, temporary directories and transaction rollbacks.
+/// Failed cleanups are queued and retried with a growing delay until they succeed
+/// or run out of attempts, what's left behind is then removed by the reconciliation.
+pub struct Cleaner {
+    in_progress: AtomicUsize,
+    idle: Notify,
+    retry_queue: Mutex<Vec<QueuedTask>>,
+    succeeded: AtomicU64,
+    failed_attempts: AtomicU64,
+    succeeded_after_retry: AtomicU64,
+    given_up: AtomicU64,
+}
+
+impl Cleaner {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Cleaner {
+            in_progress: AtomicUsize::new(0),
+            idle: Notify::new(),
+            retry_queue: Mutex::new(Vec::new()),
+            succeeded: AtomicU64::new(0),
+            failed_attempts: AtomicU64::new(0),
+            succeeded_after_retry: AtomicU64::new(0),
+            given_up: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn run(&self, task: CleanupTask) {
+        self.in_progress.fetch_add(1, Ordering::SeqCst);
+        self.attempt(QueuedTask::new(task)).await;
+        self.finish();
+    }
+
+    fn finish(&self) {
+        if self.in_progress.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// Fallback for when a resource is dropped without being closed
+    pub fn spawn(self: &Arc<Self>, task: CleanupTask) {
+        match Handle::try_current() {
+            Ok(handle) => {
+                // Counted before spawning so that shutdown can't miss it
+                self.in_progress.fetch_add(1, Ordering::SeqCst);
+                let cleaner = self.clone();
+                handle.spawn(async move {
+                    cleaner.attempt(QueuedTask::new(task)).await;
+                    cleaner.finish();
+                });
+            }
+            Err(e) => {
+                eprintln!(
+                    "Could not spawn cleanup of {}, queueing it for retry: {e}",
+                    task.describe()
+                );
+                self.enqueue(QueuedTask::new(task));
+            }
+        }
+    }
+
+    async fn attempt(&self, mut queued: QueuedTask) {
+        match queued.task.run().await {
+            Ok(()) => {
+                self.succeeded.fetch_add(1, Ordering::SeqCst);
+                if queued.attempts > 0 {
+                    self.succeeded_after_retry.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            Err(e) => {
+                self.failed_attempts.fetch_add(1, Ordering::SeqCst);
+                queued.attempts += 1;
+                if queued.attempts >= MAX_ATTEMPTS {
+                    // Dropping the task releases the box ID it holds
+                    self.given_up.fetch_add(1, Ordering::SeqCst);
+                    eprintln!(
+                        "Cleanup of {} failed {} times, giving up on it\nError: {e}",
+                        queued.task.describe(),
+                        queued.attempts
+                    );
+                    return;
+                }
+                queued.retry_at =
+                    Instant::now() + RETRY_INTERVAL * 2u32.pow(queued.attempts as u32 - 1);
+                eprintln!(
+                    "Cleanup of {} failed (attempt {}), queueing it for retry\nError: {e}",
+                    queued.task.describe(),
+                    queued.attempts
+                );
+                self.enqueue(queued);
+            }
+        }
+    }
+
+    fn enqueue(&self, queued: QueuedTask) {
+        match self.retry_queue.lock() {
+            Ok(mut queue) => queue.push(queued),
+            Err(e) => eprintln!(
+                "Failed to lock cleanup retry queue, dropping cleanup of {}: {e}",
+                queued.task.describe()
+            ),
+        }
+    }
+
+    /// Retries the queued cleanups that are due, or all of them when `all` is set
+    async fn retry_queued(&self, all: bool) {
+        let now = Instant::now();
+        let queued: Vec<QueuedTask> = match self.retry_queue.lock() {
+            Ok(mut queue) => {
+                let (due, not_due) = queue
+                    .drain(..)
+                    .partition(|queued| all || queued.retry_at <= now);
+                *queue = not_due;
+                due
+            }
+            Err(e) => {
+                eprintln!("Failed to lock cleanup retry queue: {e}");
+                return;
+            }
+        };
+        for task in queued {
+            self.attempt(task).await;
+        }
+    }
+
+    pub fn start_retry_loop(self: &Arc<Self>) {
+        let cleaner = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(RETRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                cleaner.retry_queued(false).await;
+            }
+        });
+    }
+
+    /// Waits for all in-progress cleanups, then retries the queued ones a final time
+    pub async fn shutdown(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.in_progress.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            idle.await;
+        }
+        self.retry_queued(true).await;
+        let remaining = self.metrics().queued_for_retry;
+        if remaining > 0 {
+            eprintln!("{remaining} cleanup(s) could not be completed before shutdown");
+        }
+    }
+
+    pub fn metrics(&self) -> CleanupMetrics {
+        CleanupMetrics {
+            in_progress: self.in_progress.load(Ordering::SeqCst),
+            queued_for_retry: self.retry_queue.lock().map_or(0, |queue| queue.len()),
+            succeeded: self.succeeded.load(Ordering::SeqCst),
+            failed_attempts: self.failed_attempts.load(Ordering::SeqCst),
+            succeeded_after_retry: self.succeeded_after_retry.load(Ordering::SeqCst),
+            given_up: self.given_up.load(Ordering::SeqCst),
+        }
+    }
+}
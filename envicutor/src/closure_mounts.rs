@@ -0,0 +1,49 @@
+use std::io;
+
+use anyhow::{anyhow, Error};
+use tokio::fs;
+
+use crate::{api::installation::read_script, nix, runtime_env::read_submission_env};
+
+/// The store paths that are mounted into the boxes of a runtime
+pub const MOUNTS_FILE: &str = "mounts.json";
+
+/// Store paths mentioned by the environment or the scripts of a runtime that exist in the store,
+/// e.g. the output of the shell itself is mentioned but never built
+async fn find_runtime_store_paths(runtime_dir: &str) -> Result<Vec<String>, Error> {
+    let env = read_submission_env(runtime_dir).await?;
+    let mut text: Vec<String> = env.into_values().collect();
+    text.push(read_script(&format!("{runtime_dir}/compile")).await?);
+    text.push(read_script(&format!("{runtime_dir}/run")).await?);
+    let mut store_paths = Vec::new();
+    for store_path in nix::find_store_paths(&text.join("\n")) {
+        if fs::try_exists(&store_path)
+            .await
+            .map_err(|e| anyhow!("Failed to check if {store_path} exists: {e}"))?
+        {
+            store_paths.push(store_path);
+        }
+    }
+    Ok(store_paths)
+}
+
+/// Computes the closure of a runtime and caches it in its directory
+pub async fn write_closure_mounts(runtime_dir: &str) -> Result<Vec<String>, Error> {
+    let closure = nix::get_closure(&find_runtime_store_paths(runtime_dir).await?).await?;
+    let mounts =
+        serde_json::to_string(&closure).map_err(|e| anyhow!("Failed to serialize mounts: {e}"))?;
+    // Executions of a runtime installed before mounts were cached may write them concurrently
+    crate::fs::write_file_atomically(&format!("{runtime_dir}/{MOUNTS_FILE}"), &mounts).await?;
+    Ok(closure)
+}
+
+/// Runtimes installed before mounts were cached get them computed on first use
+pub async fn read_closure_mounts(runtime_dir: &str) -> Result<Vec<String>, Error> {
+    let mounts_path = format!("{runtime_dir}/{MOUNTS_FILE}");
+    match fs::read_to_string(&mounts_path).await {
+        Ok(mounts) => serde_json::from_str(&mounts)
+            .map_err(|e| anyhow!("Failed to parse {mounts_path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => write_closure_mounts(runtime_dir).await,
+        Err(e) => Err(anyhow!("Failed to read {mounts_path}\nError: {e}")),
+    }
+}
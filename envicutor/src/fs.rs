@@ -1,7 +1,10 @@
-use std::fs::Permissions;
+use std::{
+    fs::Permissions,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use anyhow::{anyhow, Error};
-use tokio::fs;
+use tokio::{fs, io};
 
 pub async fn create_dir_replacing_existing(path: &String) -> Result<(), Error> {
     if fs::try_exists(&path)
@@ -32,3 +35,63 @@ pub async fn write_file_and_set_permissions(
         .map_err(|e| anyhow!("Failed to write permissions on {path}\nError: {e}"))?;
     Ok(())
 }
+
+pub async fn remove_file_if_exists(path: &str) -> Result<(), Error> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(anyhow!("Failed to remove: {path}\nError: {e}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn remove_dir_if_exists(path: &str) -> Result<(), Error> {
+    match fs::remove_dir_all(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(anyhow!("Failed to remove: {path}\nError: {e}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn exists(path: &str) -> Result<bool, Error> {
+    fs::try_exists(path)
+        .await
+        .map_err(|e| anyhow!("Failed to check if {path} exists: {e}"))
+}
+
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Readers never see a partially written file, even if several writers race
+pub async fn write_file_atomically(path: &str, content: &str) -> Result<(), Error> {
+    let temp_path = format!("{path}.{}", NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed));
+    fs::write(&temp_path, content)
+        .await
+        .map_err(|e| anyhow!("Failed to write {temp_path}\nError: {e}"))?;
+    fs::rename(&temp_path, path)
+        .await
+        .map_err(|e| anyhow!("Failed to move {temp_path} to {path}\nError: {e}"))
+}
+
+/// Lists the directories named by a number, i.e. runtimes or their versions
+pub async fn list_numbered_dirs(dir: &str) -> Result<Vec<String>, Error> {
+    let mut entries = fs::read_dir(dir)
+        .await
+        .map_err(|e| anyhow!("Failed to read {dir}\nError: {e}"))?;
+    let mut dirs = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| anyhow!("Failed to read an entry of {dir}\nError: {e}"))?
+    {
+        let is_dir = entry
+            .file_type()
+            .await
+            .map_err(|e| anyhow!("Failed to get the file type of {:?}: {e}", entry.path()))?
+            .is_dir();
+        if is_dir && entry.file_name().to_string_lossy().parse::<u32>().is_ok() {
+            dirs.push(entry.path().to_string_lossy().to_string());
+        }
+    }
+    Ok(dirs)
+}
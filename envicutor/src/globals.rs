@@ -2,3 +2,8 @@ pub const RUNTIMES_DIR: &str = "/envicutor/runtimes";
 pub const DB_PATH: &str = "/envicutor/runtimes/runtimes.db";
 pub const MAX_BOX_ID: u64 = 999;
 pub const TEMP_DIR: &str = "/envicutor/tmp";
+pub const ISOLATE_PATH: &str = "/usr/local/bin/isolate";
+pub const ISOLATE_BOXES_DIR: &str = "/var/local/lib/isolate";
+pub const ISOLATE_CGROUP_DIR: &str = "/sys/fs/cgroup/isolate";
+pub const STAGING_DIR: &str = "/envicutor/runtimes/staging";
+pub const INSTALLATION_LOGS_DIR: &str = "/envicutor/runtimes/installations";
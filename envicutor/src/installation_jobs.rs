@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::watch,
+    task,
+};
+
+use crate::globals::{DB_PATH, INSTALLATION_LOGS_DIR};
+
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_SUCCEEDED: &str = "succeeded";
+pub const STATUS_FAILED: &str = "failed";
+pub const STATUS_CANCELLED: &str = "cancelled";
+
+#[derive(Serialize)]
+pub struct Installation {
+    pub id: u32,
+    pub name: String,
+    pub status: String,
+    pub runtime_id: Option<u32>,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// An installation that is still running, its log file is appended to until it finishes
+pub struct InstallationJob {
+    pub id: u32,
+    pub log_path: String,
+    finished: watch::Sender<bool>,
+    cancelled: watch::Sender<bool>,
+}
+
+impl InstallationJob {
+    pub async fn open_log(&self) -> Result<File, Error> {
+        OpenOptions::new()
+            .append(true)
+            .open(&self.log_path)
+            .await
+            .map_err(|e| anyhow!("Failed to open {}\nError: {e}", self.log_path))
+    }
+
+    pub async fn log(&self, line: &str) {
+        let res = match self.open_log().await {
+            Ok(mut file) => file
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .map_err(|e| anyhow!("Failed to write to {}\nError: {e}", self.log_path)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            eprintln!("{e}");
+        }
+    }
+
+    /// Resolves to `true` once the installation has finished
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.finished.subscribe()
+    }
+
+    pub async fn wait_until_finished(&self) {
+        // Can only fail if the sender is dropped, which can't happen while the job exists
+        let _ = self.subscribe().wait_for(|finished| *finished).await;
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.cancelled.borrow()
+    }
+
+    /// Resolves once the installation is cancelled
+    pub async fn cancelled(&self) {
+        let _ = self
+            .cancelled
+            .subscribe()
+            .wait_for(|cancelled| *cancelled)
+            .await;
+    }
+}
+
+pub fn get_log_path(id: u32) -> String {
+    format!("{INSTALLATION_LOGS_DIR}/{id}.log")
+}
+
+/// Keeps track of the installations that are running in the background,
+/// their status is persisted in the `installation` table
+pub struct InstallationJobs {
+    running: Mutex<HashMap<u32, Arc<InstallationJob>>>,
+}
+
+impl InstallationJobs {
+    pub fn new() -> Arc<Self> {
+        Arc::new(InstallationJobs {
+            running: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn create(&self, name: &str) -> Result<Arc<InstallationJob>, Error> {
+        let name = name.to_string();
+        let id = task::spawn_blocking(move || {
+            let connection = Connection::open(DB_PATH)
+                .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+            connection
+                .execute(
+                    "INSERT INTO installation (name, status) VALUES (?, ?)",
+                    (&name, STATUS_RUNNING),
+                )
+                .map_err(|e| anyhow!("Failed to insert installation: {e}"))?;
+            connection
+                .query_row("SELECT last_insert_rowid()", (), |row| row.get(0))
+                .map_err(|e| anyhow!("Failed to get last inserted row id: {e}"))
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+
+        let log_path = get_log_path(id);
+        File::create(&log_path)
+            .await
+            .map_err(|e| anyhow!("Failed to create {log_path}\nError: {e}"))?;
+
+        let (finished, _) = watch::channel(false);
+        let (cancelled, _) = watch::channel(false);
+        let job = Arc::new(InstallationJob {
+            id,
+            log_path,
+            finished,
+            cancelled,
+        });
+        self.running
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock running installations: {e}"))?
+            .insert(id, job.clone());
+        Ok(job)
+    }
+
+    /// Records the outcome of the installation and stops the streaming of its log,
+    /// jobs that don't install a runtime succeed without one
+    pub async fn finish(&self, job: &InstallationJob, res: Result<Option<u32>, Error>) {
+        let (status, runtime_id, error) = match res {
+            Ok(runtime_id) => (STATUS_SUCCEEDED, runtime_id, None),
+            Err(e) if job.is_cancelled() => {
+                job.log("Installation cancelled").await;
+                (STATUS_CANCELLED, None, Some(e.to_string()))
+            }
+            Err(e) => {
+                job.log(&format!("Installation failed: {e}")).await;
+                (STATUS_FAILED, None, Some(e.to_string()))
+            }
+        };
+        let id = job.id;
+        let update_res = task::spawn_blocking(move || {
+            let connection = Connection::open(DB_PATH)
+                .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+            connection
+                .execute(
+                    "UPDATE installation SET status = ?, runtime_id = ?, error = ?, finished_at = CURRENT_TIMESTAMP WHERE id = ?",
+                    (status, runtime_id, error, id),
+                )
+                .map_err(|e| anyhow!("Failed to update installation {id}: {e}"))
+        })
+        .await;
+        match update_res {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => eprintln!("{e}"),
+            Err(e) => eprintln!("Failed to spawn blocking task: {e}"),
+        }
+
+        match self.running.lock() {
+            Ok(mut running) => {
+                running.remove(&id);
+            }
+            Err(e) => eprintln!("Failed to lock running installations: {e}"),
+        }
+        job.finished.send_replace(true);
+    }
+
+    pub fn get_running(&self, id: u32) -> Result<Option<Arc<InstallationJob>>, Error> {
+        Ok(self
+            .running
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock running installations: {e}"))?
+            .get(&id)
+            .cloned())
+    }
+
+    pub async fn get(&self, id: u32) -> Result<Option<Installation>, Error> {
+        task::spawn_blocking(move || {
+            let connection = Connection::open(DB_PATH)
+                .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+            connection
+                .query_row(
+                    "SELECT id, name, status, runtime_id, error, created_at, finished_at FROM installation WHERE id = ?",
+                    [id],
+                    |row| {
+                        Ok(Installation {
+                            id: row.get(0)?,
+                            name: row.get(1)?,
+                            status: row.get(2)?,
+                            runtime_id: row.get(3)?,
+                            error: row.get(4)?,
+                            created_at: row.get(5)?,
+                            finished_at: row.get(6)?,
+                        })
+                    },
+                )
+                .optional()
+                .map_err(|e| anyhow!("Failed to get installation {id}: {e}"))
+        })
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+    }
+}
+
+/// Installations don't survive a restart, so the ones that were running are marked as failed
+pub fn fail_interrupted_installations() -> Result<usize, Error> {
+    let connection =
+        Connection::open(DB_PATH).map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+    connection
+        .execute(
+            "UPDATE installation SET status = ?, error = 'Interrupted by a restart', finished_at = CURRENT_TIMESTAMP WHERE status = ?",
+            (STATUS_FAILED, STATUS_RUNNING),
+        )
+        .map_err(|e| anyhow!("Failed to mark interrupted installations as failed: {e}"))
+}
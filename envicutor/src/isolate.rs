@@ -1,24 +1,23 @@
-use std::{process::Stdio, time::Duration};
+use std::{process::Stdio, sync::Arc};
 
 use anyhow::{anyhow, Error};
-use tokio::{
-    fs,
-    io::{self, AsyncWriteExt},
-    process::Command,
-    task::yield_now,
-    time,
-};
+use tokio::{fs, io::AsyncWriteExt, process::Command};
 
 use crate::{
-    globals::TEMP_DIR,
+    box_id::BoxId,
+    cleanup::{Cleaner, CleanupTask},
+    globals::{ISOLATE_PATH, TEMP_DIR},
     limits::MandatoryLimits,
+    runtime_env::read_submission_env,
     types::{Kilobytes, Seconds},
 };
 
 pub struct Isolate {
     box_id: u64,
+    box_id_guard: Option<BoxId>,
     metadata_file_path: String,
     run_pid: Option<u32>,
+    cleaner: Arc<Cleaner>,
     pub box_dir: String,
 }
 
@@ -35,8 +34,6 @@ pub struct StageResult {
     pub wall_time: Option<Seconds>,
 }
 
-const ISOLATE_PATH: &str = "/usr/local/bin/isolate";
-
 fn split_metadata_line(line: &str) -> (Result<&str, ()>, Result<&str, ()>) {
     let mut entry: Vec<&str> = line.split(':').collect();
     let value = match entry.pop() {
@@ -51,50 +48,17 @@ fn split_metadata_line(line: &str) -> (Result<&str, ()>, Result<&str, ()>) {
     (key, value)
 }
 
-async fn add_env_vars_from_file(cmd: &mut Command, file_path: &str) -> Result<(), Error> {
-    let env = fs::read_to_string(file_path)
-        .await
-        .map_err(|e| anyhow!("Failed to read environment variables from: {file_path}: {e}"))?;
-    let lines = env.lines();
-
-    let mut line_count = 0;
-    let mut key = String::new();
-    let mut value = String::new();
-    for line in lines {
-        if line.contains('=') {
-            if !key.is_empty() {
-                cmd.env(&key, &value);
-            }
-            let mut entry: Vec<&str> = line.split('=').collect();
-            value = match entry.pop() {
-                Some(e) => e.to_string(),
-                None => {
-                    return Err(anyhow!("Found a bad line in the env file: {file_path}"));
-                }
-            };
-            key = match entry.pop() {
-                Some(e) => e.to_string(),
-                None => {
-                    return Err(anyhow!("Found a bad line in the env file: {file_path}"));
-                }
-            };
-        } else {
-            value.push('\n');
-            value.push_str(line);
-        }
-        line_count += 1;
-        if line_count % 500 == 0 {
-            yield_now().await;
-        }
+async fn add_env_vars_from_runtime(cmd: &mut Command, runtime_dir: &str) -> Result<(), Error> {
+    for (key, value) in read_submission_env(runtime_dir).await? {
+        cmd.env(key, value);
     }
-    cmd.env(&key, &value);
     Ok(())
 }
 
 impl Isolate {
-    pub async fn init(box_id: u64) -> Result<Self, Error> {
+    pub async fn init(box_id: BoxId, cleaner: Arc<Cleaner>) -> Result<Self, Error> {
         let res = Command::new(ISOLATE_PATH)
-            .args(["--init", "--cg", &format!("-b{}", box_id)])
+            .args(["--init", "--cg", &format!("-b{}", box_id.get())])
             .output()
             .await
             .map_err(|e| anyhow!("Failed to get `isolate --init` output\nError: {e}"))?;
@@ -106,9 +70,11 @@ impl Isolate {
             ));
         }
         Ok(Isolate {
-            box_id,
-            metadata_file_path: format!("{TEMP_DIR}/{box_id}-metadata.txt"),
+            metadata_file_path: format!("{TEMP_DIR}/{}-metadata.txt", box_id.get()),
+            box_id: box_id.get(),
+            box_id_guard: Some(box_id),
             run_pid: None,
+            cleaner,
             box_dir: format!("{}/box", String::from_utf8_lossy(&res.stdout).trim()),
         })
     }
@@ -119,12 +85,12 @@ impl Isolate {
         limits: &MandatoryLimits,
         stdin: Option<&str>,
         workdir: &str,
-        env_file: Option<&str>,
+        env_runtime_dir: Option<&str>,
         cmd_args: &[&str],
     ) -> Result<StageResult, Error> {
         let mut cmd = Command::new(ISOLATE_PATH);
         cmd.arg("--run")
-            .arg(&format!("--meta={}", self.metadata_file_path))
+            .arg(format!("--meta={}", self.metadata_file_path))
             .arg("--cg")
             .arg("-s")
             .args(["-c", workdir])
@@ -146,8 +112,8 @@ impl Isolate {
             .arg("--")
             .args(cmd_args);
 
-        if let Some(env_file) = env_file {
-            add_env_vars_from_file(cmd.env_clear(), env_file).await?;
+        if let Some(runtime_dir) = env_runtime_dir {
+            add_env_vars_from_runtime(cmd.env_clear(), runtime_dir).await?;
         }
 
         let mut child = cmd
@@ -255,51 +221,26 @@ impl Isolate {
 
         Ok(result)
     }
+
+    fn take_cleanup_task(&mut self) -> Option<CleanupTask> {
+        Some(CleanupTask::Box {
+            box_id: self.box_id_guard.take()?,
+            metadata_file_path: self.metadata_file_path.clone(),
+            run_pid: self.run_pid.take(),
+        })
+    }
+
+    pub async fn close(mut self) {
+        if let Some(task) = self.take_cleanup_task() {
+            self.cleaner.run(task).await;
+        }
+    }
 }
 
 impl Drop for Isolate {
     fn drop(&mut self) {
-        let box_id = self.box_id;
-        let metadata_file_path = self.metadata_file_path.clone();
-        let run_pid_opt = self.run_pid;
-        tokio::spawn(async move {
-            if let Some(run_pid) = run_pid_opt {
-                let kill_res = Command::new("/bin/kill")
-                    .arg("-SIGABRT")
-                    .arg(run_pid.to_string())
-                    .output()
-                    .await;
-                if let Err(e) = kill_res {
-                    eprintln!(
-                        "Could not kill `isolate --run` process. Maybe it has already exited: {e}"
-                    );
-                }
-                time::sleep(Duration::from_millis(50)).await;
-            }
-            let res = Command::new(ISOLATE_PATH)
-                .args(["--cleanup", "--cg", &format!("-b{}", box_id)])
-                .output()
-                .await;
-            match res {
-                Ok(res) => {
-                    if !res.status.success() {
-                        eprintln!(
-                            "`isolate --cleanup` failed with\nstderr: {}\nstdout: {}",
-                            String::from_utf8_lossy(&res.stderr),
-                            String::from_utf8_lossy(&res.stdout)
-                        );
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to run `isolate --cleanup`\nError: {e}");
-                }
-            }
-            let res = fs::remove_file(&metadata_file_path).await;
-            if let Err(e) = res {
-                if e.kind() != io::ErrorKind::NotFound {
-                    eprintln!("Failed to remove: {metadata_file_path}\nError: {e}");
-                }
-            }
-        });
+        if let Some(task) = self.take_cleanup_task() {
+            self.cleaner.spawn(task);
+        }
     }
 }
@@ -1,5 +1,15 @@
+// Handlers return `Response<Body>` as their error type by design
+#![allow(clippy::result_large_err)]
+
 pub mod limits;
 pub mod isolate;
+pub mod box_id;
+pub mod cleanup;
+pub mod installation_jobs;
+pub mod nix;
+pub mod reconciliation;
+pub mod runtime_usage;
+pub mod runtime_versions;
 pub mod temp_dir;
 pub mod fs;
 pub mod transaction;
@@ -7,3 +17,8 @@ pub mod globals;
 pub mod types;
 pub mod strings;
 pub mod api;
+pub mod manifests;
+pub mod runtime_tests;
+pub mod runtime_env;
+pub mod closure_mounts;
+pub mod store_usage;
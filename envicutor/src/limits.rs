@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Error};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::types::{Kilobytes, Seconds};
 
@@ -7,7 +7,7 @@ pub trait GetLimits {
     fn get(&self, system_limits: &MandatoryLimits) -> Result<MandatoryLimits, Error>;
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
 pub struct Limits {
     pub wall_time: Option<Seconds>,
     pub cpu_time: Option<Seconds>,
@@ -18,6 +18,31 @@ pub struct Limits {
     pub max_number_of_processes: Option<u32>,
 }
 
+impl Limits {
+    /// Fields that aren't set are taken from `defaults`
+    pub fn or(&self, defaults: &Limits) -> Limits {
+        Limits {
+            wall_time: self.wall_time.or(defaults.wall_time),
+            cpu_time: self.cpu_time.or(defaults.cpu_time),
+            memory: self.memory.or(defaults.memory),
+            extra_time: self.extra_time.or(defaults.extra_time),
+            max_open_files: self.max_open_files.or(defaults.max_open_files),
+            max_file_size: self.max_file_size.or(defaults.max_file_size),
+            max_number_of_processes: self
+                .max_number_of_processes
+                .or(defaults.max_number_of_processes),
+        }
+    }
+}
+
+pub fn with_defaults(limits: &Option<Limits>, defaults: &Option<Limits>) -> Option<Limits> {
+    match (limits, defaults) {
+        (Some(limits), Some(defaults)) => Some(limits.or(defaults)),
+        (Some(limits), None) => Some(limits.clone()),
+        (None, defaults) => defaults.clone(),
+    }
+}
+
 impl GetLimits for Option<Limits> {
     fn get(&self, system_limits: &MandatoryLimits) -> Result<MandatoryLimits, Error> {
         match &self {
@@ -99,7 +124,7 @@ impl GetLimits for Option<Limits> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct MandatoryLimits {
     pub wall_time: Seconds,
     pub cpu_time: Seconds,
@@ -110,8 +135,30 @@ pub struct MandatoryLimits {
     pub max_number_of_processes: u32,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize)]
 pub struct SystemLimits {
     pub compile: MandatoryLimits,
     pub run: MandatoryLimits,
 }
+
+/// Default limits of a runtime, executions can still override them up to the system limits
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct RuntimeLimits {
+    pub compile: Option<Limits>,
+    pub run: Option<Limits>,
+}
+
+impl RuntimeLimits {
+    pub fn get(&self, system_limits: &SystemLimits) -> Result<SystemLimits, Error> {
+        Ok(SystemLimits {
+            compile: self
+                .compile
+                .get(&system_limits.compile)
+                .map_err(|e| anyhow!("Invalid compile limits: {e}"))?,
+            run: self
+                .run
+                .get(&system_limits.run)
+                .map_err(|e| anyhow!("Invalid run limits: {e}"))?,
+        })
+    }
+}
@@ -1,27 +1,51 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
-    path::Path,
+    fmt::Display,
     str::FromStr,
-    sync::{atomic::AtomicU64, Arc},
+    sync::{Arc, Mutex},
 };
 
 use axum::{
     body::Body,
+    extract::DefaultBodyLimit,
     response::{IntoResponse, Response},
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 use envicutor::{
     api::{
+        aliases::{delete_alias, list_aliases, set_alias},
+        bundles::{export_runtime, export_runtimes, import_runtimes},
+        cleanup::get_cleanup_metrics,
+        closures::{export_runtime_closure, import_runtime_closure},
         deletion::delete_runtime,
+        editing::{patch_runtime, rebuild_runtime, rebuild_runtimes, replace_runtime},
         execution::execute,
-        installation::{install_runtime, update_nix},
-        listing::list_runtimes,
+        installation::{
+            cancel_installation, get_installation, get_installation_logs, install_runtime,
+        },
+        listing::{get_runtime, list_runtimes},
+        manifests::{get_manifests_plan, sync_manifests},
+        reconciliation::get_reconciliation_report,
+        store::get_store_usage_report,
+        tags::set_runtime_tags,
+        testing::test_runtime,
+        updates::{get_nix_status, rollback_nix, update_nix},
+        versions::{list_runtime_versions, rollback_runtime},
     },
-    globals::{DB_PATH, RUNTIMES_DIR},
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    globals::{DB_PATH, INSTALLATION_LOGS_DIR, STAGING_DIR},
+    installation_jobs::{fail_interrupted_installations, InstallationJobs},
     limits::{MandatoryLimits, SystemLimits},
-    types::{Metadata, Runtime, WholeSeconds},
+    manifests::ManifestSync,
+    nix::{self, NixSettings},
+    reconciliation::{reconcile_and_log, start_reconciliation_loop},
+    runtime_env::migrate_legacy_env_files,
+    runtime_usage::RuntimeUsage,
+    runtime_versions::{is_compiled, migrate_unversioned_runtimes, read_limits},
+    types::{Aliases, Metadata, Runtime, WholeSeconds},
 };
 use rusqlite::Connection;
 use tokio::{
@@ -30,6 +54,8 @@ use tokio::{
 };
 
 const DEFAULT_PORT: &str = "5000";
+const DEFAULT_RECONCILIATION_INTERVAL: WholeSeconds = 300;
+const DEFAULT_DELETION_TIMEOUT: WholeSeconds = 60;
 
 fn get_mandatory_parsed_env_var<T>(var_name: &str) -> T
 where
@@ -43,6 +69,27 @@ where
         })
 }
 
+/// Unset and empty variables are both treated as not configured
+fn get_optional_env_var(var_name: &str) -> Option<String> {
+    env::var(var_name).ok().filter(|value| !value.is_empty())
+}
+
+/// For variables added after deployments were set up, so they keep starting without them
+fn get_parsed_env_var_or_default<T>(var_name: &str, default: T) -> T
+where
+    T: FromStr + Display,
+{
+    match get_optional_env_var(var_name) {
+        Some(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid {var_name} environment variable")),
+        None => {
+            eprintln!("Could not find {var_name} environment variable, defaulting to {default}");
+            default
+        }
+    }
+}
+
 fn get_limits_from_env_var(prefix: &str) -> MandatoryLimits {
     MandatoryLimits {
         wall_time: get_mandatory_parsed_env_var(&format!("{prefix}_WALL_TIME")),
@@ -72,7 +119,11 @@ fn get_runtimes() -> Metadata {
     let connection = Connection::open(DB_PATH)
         .unwrap_or_else(|e| panic!("Failed to open SQLite connection: {e}"));
     let mut stmt = connection
-        .prepare("SELECT id, name, source_file_name FROM runtime")
+        .prepare(
+            "SELECT runtime.id, runtime.name, runtime_version.source_file_name, runtime_version.version
+            FROM runtime JOIN runtime_version
+            ON runtime_version.runtime_id = runtime.id AND runtime_version.is_active",
+        )
         .unwrap_or_else(|e| panic!("Failed to prepare SQL statement: {}", e));
     let mut metadata_cache = HashMap::new();
     let runtime_iter = stmt
@@ -80,98 +131,537 @@ fn get_runtimes() -> Metadata {
             let id: u32 = row.get(0)?;
             let name: String = row.get(1)?;
             let source_file_name: String = row.get(2)?;
-            Ok((id, name, source_file_name))
+            let version: u32 = row.get(3)?;
+            Ok((id, name, source_file_name, version))
         })
         .unwrap_or_else(|e| {
             panic!("Failed to get id and name from the row: {e}");
         });
 
     for runtime in runtime_iter {
-        let (id, name, source_file_name) = runtime.unwrap_or_else(|e| {
+        let (id, name, source_file_name, version) = runtime.unwrap_or_else(|e| {
             panic!("Failed to get runtime from database: {e}");
         });
-        eprintln!("Loading {id}: {name}");
+        eprintln!("Loading {id}: {name} (version {version})");
         metadata_cache.insert(
             id,
             Runtime {
                 name,
                 source_file_name,
-                is_compiled: Path::new(&format!("{RUNTIMES_DIR}/{id}/compile"))
-                    .try_exists()
-                    .unwrap_or_else(|e| {
-                        panic!("Could not check if compile script exists: {e}");
-                    }),
+                is_compiled: is_compiled(id, version).unwrap_or_else(|e| panic!("{e}")),
+                version,
+                tags: Vec::new(),
+                limits: read_limits(id, version).unwrap_or_else(|e| panic!("{e}")),
             },
         );
     }
+
+    // Runtimes whose migration failed are migrated again at the next startup
+    let mut stmt = connection
+        .prepare(
+            "SELECT id, name FROM runtime
+            WHERE id NOT IN (SELECT runtime_id FROM runtime_version WHERE is_active)",
+        )
+        .unwrap_or_else(|e| panic!("Failed to prepare SQL statement: {}", e));
+    let inactive_iter = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })
+        .unwrap_or_else(|e| panic!("Failed to get runtimes without an active version: {e}"));
+    for runtime in inactive_iter {
+        let (id, name) =
+            runtime.unwrap_or_else(|e| panic!("Failed to get runtime from the row: {e}"));
+        eprintln!("Not loading {id}: {name}, it has no active version");
+    }
+
+    let mut stmt = connection
+        .prepare("SELECT runtime_id, tag FROM runtime_tag ORDER BY tag")
+        .unwrap_or_else(|e| panic!("Failed to prepare SQL statement: {}", e));
+    let tag_iter = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })
+        .unwrap_or_else(|e| panic!("Failed to get tags: {e}"));
+    for tag in tag_iter {
+        let (id, tag) = tag.unwrap_or_else(|e| panic!("Failed to get tag from the row: {e}"));
+        if let Some(runtime) = metadata_cache.get_mut(&id) {
+            runtime.tags.push(tag);
+        }
+    }
     metadata_cache
 }
 
+fn get_aliases() -> Aliases {
+    let connection = Connection::open(DB_PATH)
+        .unwrap_or_else(|e| panic!("Failed to open SQLite connection: {e}"));
+    let mut stmt = connection
+        .prepare(
+            "SELECT alias, runtime_id FROM runtime_alias
+            WHERE runtime_id IN (SELECT id FROM runtime)",
+        )
+        .unwrap_or_else(|e| panic!("Failed to prepare SQL statement: {}", e));
+    stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap_or_else(|e| panic!("Failed to get aliases: {e}"))
+        .collect::<Result<Aliases, _>>()
+        .unwrap_or_else(|e| panic!("Failed to get alias from the row: {e}"))
+}
+
 #[tokio::main]
 async fn main() {
     let installation_timeout: WholeSeconds = get_mandatory_parsed_env_var("INSTALLATION_TIMEOUT");
     let update_timeout: WholeSeconds = get_mandatory_parsed_env_var("UPDATE_TIMEOUT");
+    let deletion_timeout =
+        get_parsed_env_var_or_default("DELETION_TIMEOUT", DEFAULT_DELETION_TIMEOUT);
+    let reconciliation_interval =
+        get_parsed_env_var_or_default("RECONCILIATION_INTERVAL", DEFAULT_RECONCILIATION_INTERVAL);
     let system_limits = check_and_get_system_limits();
     let max_concurrent_submissions: usize =
         get_mandatory_parsed_env_var("MAX_CONCURRENT_SUBMISSIONS");
     let execution_semaphore = Arc::new(Semaphore::new(max_concurrent_submissions));
 
-    let box_id = Arc::new(AtomicU64::new(0));
+    nix::configure(NixSettings {
+        substituters: get_optional_env_var("NIX_SUBSTITUTERS"),
+        trusted_public_keys: get_optional_env_var("NIX_TRUSTED_PUBLIC_KEYS"),
+        cache_upload_url: get_optional_env_var("NIX_CACHE_UPLOAD_URL"),
+    });
+
+    let box_id_allocator = BoxIdAllocator::new();
+    let cleaner = Cleaner::new();
+    cleaner.start_retry_loop();
+    migrate_unversioned_runtimes(installation_timeout)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to migrate unversioned runtimes: {e}"));
+    migrate_legacy_env_files()
+        .await
+        .unwrap_or_else(|e| panic!("Failed to migrate environment files: {e}"));
     let metadata_cache = Arc::new(RwLock::new(get_runtimes()));
+    let aliases_cache = Arc::new(RwLock::new(get_aliases()));
+    let runtime_usage = RuntimeUsage::new();
+    let installations = Arc::new(Mutex::new(HashSet::new()));
+    let installation_jobs = InstallationJobs::new();
     let installation_lock = Arc::new(RwLock::new(0));
+    std::fs::create_dir_all(STAGING_DIR)
+        .unwrap_or_else(|e| panic!("Failed to create {STAGING_DIR}: {e}"));
+    std::fs::create_dir_all(INSTALLATION_LOGS_DIR)
+        .unwrap_or_else(|e| panic!("Failed to create {INSTALLATION_LOGS_DIR}: {e}"));
+    let interrupted_installations = fail_interrupted_installations()
+        .unwrap_or_else(|e| panic!("Failed to update interrupted installations: {e}"));
+    if interrupted_installations > 0 {
+        eprintln!("Marked {interrupted_installations} interrupted installation(s) as failed");
+    }
+    reconcile_and_log(&box_id_allocator, &runtime_usage).await;
+    start_reconciliation_loop(
+        reconciliation_interval,
+        box_id_allocator.clone(),
+        runtime_usage.clone(),
+    );
+    let manifest_sync = env::var("MANIFESTS_DIR").ok().map(|dir| {
+        Arc::new(ManifestSync {
+            dir,
+            installation_timeout,
+            deletion_timeout,
+            box_id_allocator: box_id_allocator.clone(),
+            cleaner: cleaner.clone(),
+            metadata_cache: metadata_cache.clone(),
+            aliases_cache: aliases_cache.clone(),
+            runtime_usage: runtime_usage.clone(),
+            installations: installations.clone(),
+            installation_jobs: installation_jobs.clone(),
+            installation_lock: installation_lock.clone(),
+            system_limits: system_limits.clone(),
+        })
+    });
+    if let Some(manifest_sync) = manifest_sync.clone() {
+        let prune = env::var("MANIFESTS_PRUNE").is_ok_and(|prune| prune == "true");
+        tokio::spawn(async move { manifest_sync.sync_and_log(prune).await });
+    }
     let app = Router::new()
         .route("/health", get(get_health))
         .route(
             "/runtimes",
             get({
                 let metadata_cache = metadata_cache.clone();
-                move || list_runtimes(metadata_cache)
+                move |query, headers| list_runtimes(query, headers, metadata_cache)
             }),
         )
         .route(
             "/runtimes",
             post({
-                let box_id = box_id.clone();
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
                 let metadata_cache = metadata_cache.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_jobs = installation_jobs.clone();
                 let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
                 move |req| {
                     install_runtime(
                         installation_timeout,
-                        box_id,
+                        box_id_allocator,
+                        cleaner,
                         metadata_cache,
+                        installations,
+                        aliases_cache,
+                        installation_jobs,
                         installation_lock,
+                        system_limits,
                         req,
                     )
                 }
             }),
         )
+        .route(
+            "/installations/:id",
+            get({
+                let installation_jobs = installation_jobs.clone();
+                move |id| get_installation(id, installation_jobs)
+            })
+            .delete({
+                let installation_jobs = installation_jobs.clone();
+                move |id| cancel_installation(id, installation_jobs)
+            }),
+        )
+        .route(
+            "/installations/:id/logs",
+            get({
+                let installation_jobs = installation_jobs.clone();
+                move |id| get_installation_logs(id, installation_jobs)
+            }),
+        )
         .route(
             "/runtimes/:id",
             delete({
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let aliases_cache = aliases_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                move |req, query| {
+                    delete_runtime(
+                        req,
+                        query,
+                        deletion_timeout,
+                        cleaner,
+                        metadata_cache,
+                        aliases_cache,
+                        runtime_usage,
+                    )
+                }
+            })
+            .put({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move |id, req| {
+                    replace_runtime(
+                        id,
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        runtime_usage,
+                        installations,
+                        aliases_cache,
+                        installation_lock,
+                        system_limits,
+                        req,
+                    )
+                }
+            })
+            .patch({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move |id, req| {
+                    patch_runtime(
+                        id,
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        runtime_usage,
+                        installations,
+                        aliases_cache,
+                        installation_lock,
+                        system_limits,
+                        req,
+                    )
+                }
+            })
+            .get({
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let system_limits = system_limits.clone();
+                move |id, query| {
+                    get_runtime(id, query, metadata_cache, runtime_usage, system_limits)
+                }
+            }),
+        )
+        .route(
+            "/runtimes/export",
+            get({
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                move || export_runtimes(metadata_cache, runtime_usage)
+            }),
+        )
+        .route(
+            "/runtimes/import",
+            post({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_jobs = installation_jobs.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move |req| {
+                    import_runtimes(
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        installations,
+                        aliases_cache,
+                        installation_jobs,
+                        installation_lock,
+                        system_limits,
+                        req,
+                    )
+                }
+            }),
+        )
+        .route(
+            "/runtimes/import/closure",
+            post({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_jobs = installation_jobs.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move |body| {
+                    import_runtime_closure(
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        installations,
+                        aliases_cache,
+                        installation_jobs,
+                        installation_lock,
+                        system_limits,
+                        body,
+                    )
+                }
+            })
+            // Closures are usually much larger than the default limit
+            .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/runtimes/:id/export",
+            get({
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                move |id| export_runtime(id, metadata_cache, runtime_usage)
+            }),
+        )
+        .route(
+            "/runtimes/:id/export/closure",
+            get({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                move |id| {
+                    export_runtime_closure(
+                        id,
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        runtime_usage,
+                    )
+                }
+            }),
+        )
+        .route(
+            "/runtimes/:id/tags",
+            put({
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installations = installations.clone();
+                move |id, req| {
+                    set_runtime_tags(id, metadata_cache, runtime_usage, installations, req)
+                }
+            }),
+        )
+        .route(
+            "/runtimes/rebuild",
+            post({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_jobs = installation_jobs.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move || {
+                    rebuild_runtimes(
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        runtime_usage,
+                        installations,
+                        aliases_cache,
+                        installation_jobs,
+                        installation_lock,
+                        system_limits,
+                    )
+                }
+            }),
+        )
+        .route(
+            "/runtimes/:id/rebuild",
+            post({
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installations = installations.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move |id| {
+                    rebuild_runtime(
+                        id,
+                        installation_timeout,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        runtime_usage,
+                        installations,
+                        aliases_cache,
+                        installation_lock,
+                        system_limits,
+                    )
+                }
+            }),
+        )
+        .route(
+            "/runtimes/:id/test",
+            post({
+                let execution_semaphore = execution_semaphore.clone();
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installation_lock = installation_lock.clone();
+                let system_limits = system_limits.clone();
+                move |id, query| {
+                    test_runtime(
+                        id,
+                        query,
+                        execution_semaphore,
+                        box_id_allocator,
+                        cleaner,
+                        metadata_cache,
+                        runtime_usage,
+                        installation_lock,
+                        system_limits,
+                    )
+                }
+            }),
+        )
+        .route(
+            "/runtimes/:id/versions",
+            get({
                 let metadata_cache = metadata_cache.clone();
-                move |req| delete_runtime(req, metadata_cache)
+                move |id| list_runtime_versions(id, metadata_cache)
+            }),
+        )
+        .route(
+            "/runtimes/:id/rollback",
+            post({
+                let metadata_cache = metadata_cache.clone();
+                let runtime_usage = runtime_usage.clone();
+                let installations = installations.clone();
+                move |id, req| {
+                    rollback_runtime(id, metadata_cache, runtime_usage, installations, req)
+                }
+            }),
+        )
+        .route(
+            "/aliases",
+            get({
+                let aliases_cache = aliases_cache.clone();
+                move || list_aliases(aliases_cache)
+            }),
+        )
+        .route(
+            "/aliases/:alias",
+            put({
+                let metadata_cache = metadata_cache.clone();
+                let aliases_cache = aliases_cache.clone();
+                let installations = installations.clone();
+                move |alias, req| {
+                    set_alias(alias, metadata_cache, aliases_cache, installations, req)
+                }
+            })
+            .delete({
+                let aliases_cache = aliases_cache.clone();
+                move |alias| delete_alias(alias, aliases_cache)
             }),
         )
         .route(
             "/update",
+            get(get_nix_status).post({
+                let installation_lock = installation_lock.clone();
+                move |body| update_nix(update_timeout, installation_lock, body)
+            }),
+        )
+        .route(
+            "/update/rollback",
             post({
                 let installation_lock = installation_lock.clone();
-                move || update_nix(update_timeout, installation_lock)
+                move || rollback_nix(installation_lock)
             }),
         )
         .route(
             "/execute",
             post({
                 let metadata_cache = metadata_cache.clone();
+                let aliases_cache = aliases_cache.clone();
+                let runtime_usage = runtime_usage.clone();
                 let installation_lock = installation_lock.clone();
-                let box_id = box_id.clone();
+                let box_id_allocator = box_id_allocator.clone();
+                let cleaner = cleaner.clone();
                 let system_limits = system_limits.clone();
                 let execution_semaphore = execution_semaphore.clone();
                 move |query, req| {
                     execute(
                         execution_semaphore,
-                        box_id,
+                        box_id_allocator,
+                        cleaner,
                         metadata_cache,
+                        aliases_cache,
+                        runtime_usage,
                         installation_lock,
                         system_limits,
                         req,
@@ -179,6 +669,42 @@ async fn main() {
                     )
                 }
             }),
+        )
+        .route(
+            "/cleanup",
+            get({
+                let cleaner = cleaner.clone();
+                move || get_cleanup_metrics(cleaner)
+            }),
+        )
+        .route(
+            "/store/usage",
+            get({
+                let metadata_cache = metadata_cache.clone();
+                move || get_store_usage_report(metadata_cache)
+            }),
+        )
+        .route(
+            "/reconciliation",
+            get({
+                let box_id_allocator = box_id_allocator.clone();
+                let runtime_usage = runtime_usage.clone();
+                move || get_reconciliation_report(box_id_allocator, runtime_usage)
+            }),
+        )
+        .route(
+            "/manifests/plan",
+            get({
+                let manifest_sync = manifest_sync.clone();
+                move |query| get_manifests_plan(query, manifest_sync)
+            }),
+        )
+        .route(
+            "/manifests/sync",
+            post({
+                let manifest_sync = manifest_sync.clone();
+                move |query| sync_manifests(query, manifest_sync)
+            }),
         );
 
     let port = env::var("PORT").unwrap_or_else(|_| {
@@ -202,4 +728,7 @@ async fn main() {
         .with_graceful_shutdown(signal)
         .await
         .expect("Failed to start server");
+
+    eprintln!("Waiting for pending cleanups...");
+    cleaner.shutdown().await;
 }
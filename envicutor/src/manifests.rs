@@ -0,0 +1,418 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::RwLock};
+
+use crate::{
+    api::{
+        deletion::remove_runtime,
+        editing::{edit_runtime, get_current_definition},
+        errors::ApiError,
+        installation::{
+            prepare_request, start_installation, AddRuntimeRequest, EnvironmentSource,
+            NameReservation,
+        },
+        tags::{prepare_tags, set_tags},
+    },
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    installation_jobs::InstallationJobs,
+    limits::{RuntimeLimits, SystemLimits},
+    nix::NixpkgsPin,
+    runtime_env::EnvSettings,
+    runtime_tests::RuntimeTest,
+    runtime_usage::RuntimeUsage,
+    runtime_versions::get_version_dir,
+    types::{Aliases, Metadata, Runtime, WholeSeconds},
+};
+
+/// A runtime as declared in a `.toml` or `.json` file of the manifests directory
+#[derive(Deserialize)]
+pub struct Manifest {
+    name: String,
+    #[serde(default)]
+    nix_shell: String,
+    flake: Option<String>,
+    nixpkgs: Option<NixpkgsPin>,
+    #[serde(default)]
+    compile_script: String,
+    run_script: String,
+    source_file_name: String,
+    #[serde(default)]
+    limits: RuntimeLimits,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    tests: Vec<RuntimeTest>,
+    #[serde(default)]
+    env_settings: EnvSettings,
+}
+
+#[derive(Serialize)]
+pub struct ManagedRuntime {
+    pub id: u32,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct InvalidManifest {
+    pub file: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Default)]
+pub struct SyncPlan {
+    pub install: Vec<String>,
+    pub update: Vec<ManagedRuntime>,
+    pub prune: Vec<ManagedRuntime>,
+    pub unchanged: Vec<String>,
+    pub invalid: Vec<InvalidManifest>,
+}
+
+#[derive(Serialize)]
+pub struct SyncResult {
+    pub name: String,
+    pub action: &'static str,
+    pub installation_id: Option<u32>,
+    pub version: Option<u32>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SyncReport {
+    pub plan: SyncPlan,
+    pub results: Vec<SyncResult>,
+}
+
+struct PlannedUpdate {
+    id: u32,
+    definition: Option<AddRuntimeRequest>,
+    tags: Option<Vec<String>>,
+}
+
+/// What has to be done to make the installed runtimes match the manifests
+pub struct Plan {
+    pub summary: SyncPlan,
+    installs: Vec<(AddRuntimeRequest, Vec<String>)>,
+    updates: Vec<PlannedUpdate>,
+    prunes: Vec<u32>,
+}
+
+/// Keeps the installed runtimes in sync with the manifests in `dir`, matching them by name
+pub struct ManifestSync {
+    pub dir: String,
+    pub installation_timeout: WholeSeconds,
+    pub deletion_timeout: WholeSeconds,
+    pub box_id_allocator: Arc<BoxIdAllocator>,
+    pub cleaner: Arc<Cleaner>,
+    pub metadata_cache: Arc<RwLock<Metadata>>,
+    pub aliases_cache: Arc<RwLock<Aliases>>,
+    pub runtime_usage: Arc<RuntimeUsage>,
+    pub installations: Arc<Mutex<HashSet<String>>>,
+    pub installation_jobs: Arc<InstallationJobs>,
+    pub installation_lock: Arc<RwLock<u8>>,
+    pub system_limits: SystemLimits,
+}
+
+fn parse_manifest(file_name: &str, content: &str) -> Result<Manifest, String> {
+    if file_name.ends_with(".toml") {
+        toml::from_str(content).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_str(content).map_err(|e| e.to_string())
+    }
+}
+
+impl ManifestSync {
+    /// Returns the valid manifests and the errors of the invalid ones
+    async fn read_manifests(
+        &self,
+    ) -> Result<(Vec<(AddRuntimeRequest, Vec<String>)>, Vec<InvalidManifest>), Error> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow!("Could not find manifests directory: {}", self.dir))
+            }
+            Err(e) => return Err(anyhow!("Failed to read {}\nError: {e}", self.dir)),
+        };
+        let mut file_names = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| anyhow!("Failed to read an entry of {}\nError: {e}", self.dir))?
+        {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name.ends_with(".toml") || file_name.ends_with(".json") {
+                file_names.push(file_name);
+            }
+        }
+        file_names.sort();
+
+        let mut manifests = Vec::new();
+        let mut invalid = Vec::new();
+        let mut names = HashSet::new();
+        for file_name in file_names {
+            let path = Path::new(&self.dir).join(&file_name);
+            let content = fs::read_to_string(&path)
+                .await
+                .map_err(|e| anyhow!("Failed to read {}\nError: {e}", path.display()))?;
+            let manifest = match parse_manifest(&file_name, &content) {
+                Ok(manifest) => manifest,
+                Err(error) => {
+                    invalid.push(InvalidManifest {
+                        file: file_name,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            let mut req = AddRuntimeRequest {
+                name: manifest.name,
+                nix_shell: manifest.nix_shell,
+                flake: manifest.flake,
+                nixpkgs: manifest.nixpkgs,
+                compile_script: manifest.compile_script,
+                run_script: manifest.run_script,
+                source_file_name: manifest.source_file_name,
+                limits: manifest.limits,
+                tests: manifest.tests,
+                env_settings: manifest.env_settings,
+            };
+            let mut tags = manifest.tags;
+            let validation_res = match prepare_request(&mut req, &self.system_limits).await {
+                Ok(()) => prepare_tags(&mut tags),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = validation_res {
+                invalid.push(InvalidManifest {
+                    file: file_name,
+                    error: e.into_message(),
+                });
+                continue;
+            }
+            if !names.insert(req.name.clone()) {
+                invalid.push(InvalidManifest {
+                    file: file_name,
+                    error: format!("Another manifest declares a runtime named: {}", req.name),
+                });
+                continue;
+            }
+            manifests.push((req, tags));
+        }
+        Ok((manifests, invalid))
+    }
+
+    pub async fn plan(&self, prune: bool) -> Result<Plan, Error> {
+        let (manifests, invalid) = self.read_manifests().await?;
+        let mut installed: HashMap<String, (u32, Runtime)> = self
+            .metadata_cache
+            .read()
+            .await
+            .iter()
+            .map(|(id, runtime)| (runtime.name.clone(), (*id, runtime.clone())))
+            .collect();
+
+        let mut plan = Plan {
+            summary: SyncPlan {
+                invalid,
+                ..Default::default()
+            },
+            installs: Vec::new(),
+            updates: Vec::new(),
+            prunes: Vec::new(),
+        };
+        for (req, tags) in manifests {
+            let Some((id, runtime)) = installed.remove(&req.name) else {
+                plan.summary.install.push(req.name.clone());
+                plan.installs.push((req, tags));
+                continue;
+            };
+            let current =
+                get_current_definition(&get_version_dir(id, runtime.version), &runtime).await?;
+            let definition = (current != req).then_some(req);
+            let tags = (runtime.tags != tags).then_some(tags);
+            if definition.is_none() && tags.is_none() {
+                plan.summary.unchanged.push(runtime.name);
+                continue;
+            }
+            plan.summary.update.push(ManagedRuntime {
+                id,
+                name: runtime.name,
+            });
+            plan.updates.push(PlannedUpdate {
+                id,
+                definition,
+                tags,
+            });
+        }
+        if prune {
+            let mut extras: Vec<(u32, String)> = installed
+                .into_iter()
+                .map(|(name, (id, _))| (id, name))
+                .collect();
+            extras.sort();
+            for (id, name) in extras {
+                plan.summary.prune.push(ManagedRuntime { id, name });
+                plan.prunes.push(id);
+            }
+        }
+        plan.summary.install.sort();
+        plan.summary.unchanged.sort();
+        Ok(plan)
+    }
+
+    async fn update(&self, update: PlannedUpdate) -> Result<Option<u32>, ApiError> {
+        let mut version = None;
+        if let Some(definition) = update.definition {
+            version = Some(
+                edit_runtime(
+                    update.id,
+                    self.installation_timeout,
+                    self.box_id_allocator.clone(),
+                    self.cleaner.clone(),
+                    self.metadata_cache.clone(),
+                    self.runtime_usage.clone(),
+                    self.installations.clone(),
+                    self.aliases_cache.clone(),
+                    self.installation_lock.clone(),
+                    self.system_limits.clone(),
+                    false,
+                    |_| definition,
+                )
+                .await?,
+            );
+        }
+        if let Some(tags) = update.tags {
+            set_tags(
+                update.id,
+                tags,
+                &self.metadata_cache,
+                &self.runtime_usage,
+                &self.installations,
+            )
+            .await?;
+        }
+        Ok(version)
+    }
+
+    async fn install(&self, req: AddRuntimeRequest, tags: Vec<String>) -> Result<u32, ApiError> {
+        let name_reservation = NameReservation::new(
+            &req.name,
+            &self.installations,
+            &self.metadata_cache,
+            &self.aliases_cache,
+        )
+        .await?;
+        start_installation(
+            self.installation_timeout,
+            self.box_id_allocator.clone(),
+            self.cleaner.clone(),
+            self.metadata_cache.clone(),
+            self.installation_jobs.clone(),
+            self.installation_lock.clone(),
+            self.system_limits.clone(),
+            name_reservation,
+            req,
+            tags,
+            EnvironmentSource::Build,
+        )
+        .await
+    }
+
+    /// Prunes, then updates, then starts installing in the background,
+    /// a failed step is reported without stopping the others
+    pub async fn apply(&self, plan: Plan) -> SyncReport {
+        let mut results = Vec::new();
+        for (id, runtime) in plan.prunes.into_iter().zip(&plan.summary.prune) {
+            let res = remove_runtime(
+                id,
+                self.deletion_timeout,
+                &self.cleaner,
+                &self.metadata_cache,
+                &self.aliases_cache,
+                &self.runtime_usage,
+            )
+            .await;
+            let error = res.err().map(ApiError::into_message);
+            results.push(SyncResult {
+                name: runtime.name.clone(),
+                action: "prune",
+                installation_id: None,
+                version: None,
+                error,
+            });
+        }
+        for (update, runtime) in plan.updates.into_iter().zip(&plan.summary.update) {
+            let (version, error) = match self.update(update).await {
+                Ok(version) => (version, None),
+                Err(e) => (None, Some(e.into_message())),
+            };
+            results.push(SyncResult {
+                name: runtime.name.clone(),
+                action: "update",
+                installation_id: None,
+                version,
+                error,
+            });
+        }
+        for (req, tags) in plan.installs {
+            let name = req.name.clone();
+            let (installation_id, error) = match self.install(req, tags).await {
+                Ok(id) => (Some(id), None),
+                Err(e) => (None, Some(e.into_message())),
+            };
+            results.push(SyncResult {
+                name,
+                action: "install",
+                installation_id,
+                version: None,
+                error,
+            });
+        }
+        SyncReport {
+            plan: plan.summary,
+            results,
+        }
+    }
+
+    /// Used at startup, nothing is applied if a manifest is invalid
+    pub async fn sync_and_log(&self, prune: bool) {
+        let plan = match self.plan(prune).await {
+            Ok(plan) => plan,
+            Err(e) => {
+                eprintln!("Failed to plan the sync of the manifests: {e}");
+                return;
+            }
+        };
+        match serde_json::to_string(&plan.summary) {
+            Ok(summary) => eprintln!("Manifests sync plan: {summary}"),
+            Err(e) => eprintln!("Failed to serialize the manifests sync plan: {e}"),
+        }
+        if !plan.summary.invalid.is_empty() {
+            eprintln!("Not syncing the manifests because some of them are invalid");
+            return;
+        }
+        let report = self.apply(plan).await;
+        for result in report.results {
+            match (result.error, result.installation_id) {
+                (Some(error), _) => eprintln!(
+                    "Failed to {} runtime {} from its manifest: {error}",
+                    result.action, result.name
+                ),
+                (None, Some(installation_id)) => eprintln!(
+                    "Installing runtime {} from its manifest (installation {installation_id})",
+                    result.name
+                ),
+                (None, None) => eprintln!(
+                    "Applied {} of runtime {} from its manifest",
+                    result.action, result.name
+                ),
+            }
+        }
+    }
+}
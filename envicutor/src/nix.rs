@@ -0,0 +1,713 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io, process,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+    fs,
+    process::Command,
+    sync::{RwLock, RwLockReadGuard},
+    time,
+};
+
+use crate::{runtime_env::ENV_FILE, types::WholeSeconds};
+
+pub const NIX_BIN_PATH: &str = "/home/envicutor/.nix-profile/bin";
+const STORE_DIR: &str = "/nix/store/";
+const NIX_SOURCE_FILE: &str = "nix.json";
+const NIX_LOCK_FILE: &str = "lock.json";
+const NIXPKGS_FILE: &str = "nixpkgs.nix";
+const FLAKE_FEATURES: [&str; 2] = ["--extra-experimental-features", "nix-command flakes"];
+/// Queries of the local store don't fetch anything, so they only take long if the store is stuck
+const QUERY_TIMEOUT: WholeSeconds = 60;
+
+/// Where Nix fetches and shares store paths, configured by the operator
+#[derive(Default)]
+pub struct NixSettings {
+    /// Replace the default substituters, e.g. `file:///cache https://cache.nixos.org`
+    pub substituters: Option<String>,
+    pub trusted_public_keys: Option<String>,
+    /// The environments of new runtimes are copied there so other nodes can substitute them
+    pub cache_upload_url: Option<String>,
+}
+
+static NIX_SETTINGS: OnceLock<NixSettings> = OnceLock::new();
+
+/// Must be called once at startup, before any Nix command runs
+pub fn configure(settings: NixSettings) {
+    if NIX_SETTINGS.set(settings).is_err() {
+        eprintln!("Nix settings were already configured");
+    }
+}
+
+fn get_settings_args() -> Vec<String> {
+    let Some(settings) = NIX_SETTINGS.get() else {
+        return Vec::new();
+    };
+    let mut args = Vec::new();
+    for (name, value) in [
+        ("substituters", &settings.substituters),
+        ("trusted-public-keys", &settings.trusted_public_keys),
+    ] {
+        if let Some(value) = value {
+            args.extend(["--option".to_string(), name.to_string(), value.clone()]);
+        }
+    }
+    args
+}
+
+/// Creates a command for a Nix program with the settings of the operator
+pub fn command(program: &str) -> Command {
+    let mut cmd = Command::new(format!("{NIX_BIN_PATH}/{program}"));
+    cmd.args(get_settings_args());
+    cmd
+}
+
+/// Creates a command for a Nix program that runs with an empty environment,
+/// so nothing from the server's environment leaks into the evaluation
+pub fn clean_env_std_command(program: &str) -> process::Command {
+    let mut cmd = process::Command::new("env");
+    cmd.arg("-i")
+        .arg("PATH=/bin")
+        .arg(format!("{NIX_BIN_PATH}/{program}"))
+        .args(get_settings_args());
+    cmd
+}
+
+pub fn clean_env_command(program: &str) -> Command {
+    Command::from(clean_env_std_command(program))
+}
+
+async fn get_stdout(cmd: &mut Command, description: &str) -> Result<String, Error> {
+    let res = cmd
+        .output()
+        .await
+        .map_err(|e| anyhow!("Failed to run {description}: {e}"))?;
+    if !res.status.success() {
+        return Err(anyhow!(
+            "{description} failed with\nstderr: {}\nstdout: {}",
+            String::from_utf8_lossy(&res.stderr),
+            String::from_utf8_lossy(&res.stdout)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&res.stdout).to_string())
+}
+
+/// Kills the command if it runs for longer than `timeout`
+async fn get_stdout_with_timeout(
+    cmd: &mut Command,
+    description: &str,
+    timeout: WholeSeconds,
+) -> Result<String, Error> {
+    cmd.kill_on_drop(true);
+    time::timeout(
+        Duration::from_secs(timeout.into()),
+        get_stdout(cmd, description),
+    )
+    .await
+    .map_err(|_| anyhow!("{description} timed out after {timeout} second(s)"))?
+}
+
+/// Only garbage collection takes it exclusively, so it waits for the store paths in use
+/// by installations and Nix updates to be rooted without blocking executions
+static GC_LOCK: RwLock<()> = RwLock::const_new(());
+
+/// Keeps garbage collection from running while store paths that aren't rooted yet are in use
+pub async fn hold_off_garbage_collection() -> RwLockReadGuard<'static, ()> {
+    GC_LOCK.read().await
+}
+
+/// A nixpkgs revision that `<nixpkgs>` resolves to instead of the channel of the profile
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct NixpkgsPin {
+    pub revision: String,
+    pub sha256: String,
+}
+
+impl NixpkgsPin {
+    /// Both are interpolated into a Nix expression, so only their expected characters are allowed
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.revision.len() != 40 || !self.revision.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!(
+                "Invalid nixpkgs revision, expected a full commit hash"
+            ));
+        }
+        if self.sha256.is_empty()
+            || !self
+                .sha256
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+/=-:".contains(c))
+        {
+            return Err(anyhow!("Invalid nixpkgs sha256"));
+        }
+        Ok(())
+    }
+
+    fn fetch_expression(&self) -> String {
+        format!(
+            "builtins.fetchTarball {{ url = \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\"; sha256 = \"{}\"; }}",
+            self.revision, self.sha256
+        )
+    }
+}
+
+/// Where the environment of a runtime comes from besides its `shell.nix`
+#[derive(Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct NixSource {
+    pub flake: Option<String>,
+    pub nixpkgs: Option<NixpkgsPin>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FlakeLock {
+    pub original_url: String,
+    pub locked_url: String,
+    pub attribute: Option<String>,
+    pub revision: Option<String>,
+    pub last_modified: Option<u64>,
+    pub path: String,
+    /// The lock file of the flake
+    pub locks: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NixpkgsLock {
+    pub revision: String,
+    pub sha256: String,
+    pub path: String,
+}
+
+/// What the source of a runtime resolved to when it was built
+#[derive(Deserialize, Serialize, Default)]
+pub struct NixLock {
+    pub flake: Option<FlakeLock>,
+    pub nixpkgs: Option<NixpkgsLock>,
+    #[serde(default)]
+    pub store_paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlakeMetadata {
+    url: String,
+    path: String,
+    revision: Option<String>,
+    last_modified: Option<u64>,
+    locks: serde_json::Value,
+}
+
+async fn read_json_if_exists<T: DeserializeOwned>(path: &str) -> Result<Option<T>, Error> {
+    match fs::read_to_string(path).await {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| anyhow!("Failed to parse {path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read {path}\nError: {e}")),
+    }
+}
+
+async fn write_json<T: Serialize>(path: &str, value: &T) -> Result<(), Error> {
+    let content =
+        serde_json::to_string(value).map_err(|e| anyhow!("Failed to serialize {path}: {e}"))?;
+    fs::write(path, content)
+        .await
+        .map_err(|e| anyhow!("Failed to write {path}\nError: {e}"))
+}
+
+/// Writes `nix.json`, and `nixpkgs.nix` if nixpkgs is pinned
+pub async fn write_source(runtime_dir: &str, source: &NixSource) -> Result<(), Error> {
+    if let Some(nixpkgs) = &source.nixpkgs {
+        let nixpkgs_path = format!("{runtime_dir}/{NIXPKGS_FILE}");
+        fs::write(
+            &nixpkgs_path,
+            format!("import ({})\n", nixpkgs.fetch_expression()),
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to write {nixpkgs_path}\nError: {e}"))?;
+    }
+    write_json(&format!("{runtime_dir}/{NIX_SOURCE_FILE}"), source).await
+}
+
+/// Runtimes installed before flakes and pins were supported only have a `shell.nix`
+pub async fn read_source(runtime_dir: &str) -> Result<NixSource, Error> {
+    Ok(
+        read_json_if_exists(&format!("{runtime_dir}/{NIX_SOURCE_FILE}"))
+            .await?
+            .unwrap_or_default(),
+    )
+}
+
+pub async fn read_lock(runtime_dir: &str) -> Result<Option<NixLock>, Error> {
+    read_json_if_exists(&format!("{runtime_dir}/{NIX_LOCK_FILE}")).await
+}
+
+/// Used when a new version keeps the environment of the previous one
+pub async fn copy_lock(from_dir: &str, to_dir: &str) -> Result<(), Error> {
+    let (from, to) = (
+        format!("{from_dir}/{NIX_LOCK_FILE}"),
+        format!("{to_dir}/{NIX_LOCK_FILE}"),
+    );
+    match fs::copy(&from, &to).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(anyhow!("Failed to copy {from} to {to}\nError: {e}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn lock_flake(flake: &str, timeout: WholeSeconds) -> Result<FlakeLock, Error> {
+    let (url, attribute) = match flake.split_once('#') {
+        Some((url, attribute)) => (url, Some(attribute.to_string())),
+        None => (flake, None),
+    };
+    let metadata = get_stdout_with_timeout(
+        clean_env_command("nix")
+            .args(FLAKE_FEATURES)
+            .args(["flake", "metadata", "--json", url]),
+        "nix flake metadata",
+        timeout,
+    )
+    .await?;
+    let metadata: FlakeMetadata = serde_json::from_str(&metadata)
+        .map_err(|e| anyhow!("Failed to parse the metadata of flake: {url}\nError: {e}"))?;
+    Ok(FlakeLock {
+        original_url: url.to_string(),
+        locked_url: metadata.url,
+        attribute,
+        revision: metadata.revision,
+        last_modified: metadata.last_modified,
+        path: metadata.path,
+        locks: metadata.locks,
+    })
+}
+
+/// Resolves the flake or the pinned nixpkgs of a runtime and records the result in `lock.json`,
+/// the environment is then built from what was locked
+pub async fn lock_source(runtime_dir: &str, timeout: WholeSeconds) -> Result<(), Error> {
+    let source = read_source(runtime_dir).await?;
+    let mut lock = NixLock::default();
+    if let Some(flake) = &source.flake {
+        lock.flake = Some(lock_flake(flake, timeout).await?);
+    }
+    if let Some(nixpkgs) = source.nixpkgs {
+        let path = get_stdout_with_timeout(
+            clean_env_command("nix-instantiate").args([
+                "--eval",
+                "--expr",
+                &nixpkgs.fetch_expression(),
+            ]),
+            "nix-instantiate --eval",
+            timeout,
+        )
+        .await?;
+        lock.nixpkgs = Some(NixpkgsLock {
+            revision: nixpkgs.revision,
+            sha256: nixpkgs.sha256,
+            path: path.trim().trim_matches('"').to_string(),
+        });
+    }
+    write_json(&format!("{runtime_dir}/{NIX_LOCK_FILE}"), &lock).await
+}
+
+async fn get_locked_flake_ref(runtime_dir: &str) -> Result<String, Error> {
+    let flake = read_lock(runtime_dir)
+        .await?
+        .and_then(|lock| lock.flake)
+        .ok_or_else(|| anyhow!("The flake of {runtime_dir} is not locked"))?;
+    Ok(match flake.attribute {
+        Some(attribute) => format!("{}#{attribute}", flake.locked_url),
+        None => flake.locked_url,
+    })
+}
+
+fn get_nixpkgs_args(runtime_dir: &str, source: &NixSource) -> Vec<String> {
+    match source.nixpkgs {
+        Some(_) => vec![
+            "-I".to_string(),
+            format!("nixpkgs={runtime_dir}/{NIXPKGS_FILE}"),
+        ],
+        None => Vec::new(),
+    }
+}
+
+/// Creates the command that prints the environment of a runtime NUL-delimited, the source has to be locked first
+pub async fn env_std_command(
+    runtime_dir: &str,
+    timeout: WholeSeconds,
+) -> Result<process::Command, Error> {
+    let source = read_source(runtime_dir).await?;
+    let timeout = ["--timeout".to_string(), timeout.to_string()];
+    if source.flake.is_some() {
+        let mut cmd = clean_env_std_command("nix");
+        cmd.args(FLAKE_FEATURES)
+            .arg("develop")
+            .args(timeout)
+            .arg(get_locked_flake_ref(runtime_dir).await?)
+            .args(["--command", "/bin/bash", "-c", "env -0"]);
+        return Ok(cmd);
+    }
+    let mut cmd = clean_env_std_command("nix-shell");
+    cmd.args(timeout)
+        .args(get_nixpkgs_args(runtime_dir, &source))
+        .arg(format!("{runtime_dir}/shell.nix"))
+        .args(["--run", "/bin/bash -c 'env -0'"]);
+    Ok(cmd)
+}
+
+/// Registers indirect GC roots in the `gcroots` directory of the runtime for the inputs of its environment,
+/// so its store paths survive garbage collection until the directory is removed.
+/// The rooted store paths are recorded in `lock.json`
+pub async fn add_gc_roots(runtime_dir: &str, timeout: WholeSeconds) -> Result<(), Error> {
+    let gc_roots_dir = format!("{runtime_dir}/gcroots");
+    crate::fs::create_dir_replacing_existing(&gc_roots_dir).await?;
+    let source = read_source(runtime_dir).await?;
+
+    let references = if source.flake.is_some() {
+        // The profile of `nix develop` is itself a GC root for the environment
+        let profile_path = format!("{gc_roots_dir}/profile");
+        get_stdout_with_timeout(
+            clean_env_command("nix")
+                .args(FLAKE_FEATURES)
+                .args(["develop", "--profile", &profile_path])
+                .arg(get_locked_flake_ref(runtime_dir).await?)
+                .args(["--command", "/bin/true"]),
+            "nix develop --profile",
+            timeout,
+        )
+        .await?;
+        get_stdout_with_timeout(
+            command("nix-store")
+                .args(["--query", "--references"])
+                .arg(&profile_path),
+            "nix-store --query --references",
+            timeout,
+        )
+        .await?
+    } else {
+        let drv_path = get_stdout_with_timeout(
+            clean_env_command("nix-instantiate")
+                .args(get_nixpkgs_args(runtime_dir, &source))
+                .arg(format!("{runtime_dir}/shell.nix"))
+                .args(["--add-root", &format!("{gc_roots_dir}/shell.drv")])
+                .arg("--indirect"),
+            "nix-instantiate",
+            timeout,
+        )
+        .await?;
+
+        let references = get_stdout_with_timeout(
+            command("nix-store")
+                .args(["--query", "--references"])
+                .arg(drv_path.trim()),
+            "nix-store --query --references",
+            timeout,
+        )
+        .await?;
+
+        get_stdout_with_timeout(
+            command("nix-store")
+                .arg("--realise")
+                .args(references.lines())
+                .args(["--add-root", &format!("{gc_roots_dir}/input")])
+                .arg("--indirect"),
+            "nix-store --realise",
+            timeout,
+        )
+        .await?;
+        references
+    };
+
+    record_store_paths(
+        runtime_dir,
+        references.lines().map(|path| path.to_string()).collect(),
+    )
+    .await
+}
+
+async fn record_store_paths(runtime_dir: &str, store_paths: Vec<String>) -> Result<(), Error> {
+    let mut lock = read_lock(runtime_dir).await?.unwrap_or_default();
+    lock.store_paths = store_paths;
+    write_json(&format!("{runtime_dir}/{NIX_LOCK_FILE}"), &lock).await
+}
+
+/// Roots store paths that are already valid, e.g. imported from a closure, instead of building
+/// the environment of the runtime
+pub async fn add_gc_roots_for_paths(
+    runtime_dir: &str,
+    store_paths: &[String],
+    timeout: WholeSeconds,
+) -> Result<(), Error> {
+    let gc_roots_dir = format!("{runtime_dir}/gcroots");
+    crate::fs::create_dir_replacing_existing(&gc_roots_dir).await?;
+    if !store_paths.is_empty() {
+        get_stdout_with_timeout(
+            command("nix-store")
+                .arg("--realise")
+                .args(store_paths)
+                .args(["--add-root", &format!("{gc_roots_dir}/input")])
+                .arg("--indirect"),
+            "nix-store --realise",
+            timeout,
+        )
+        .await?;
+    }
+    record_store_paths(runtime_dir, store_paths.to_vec()).await
+}
+
+/// Finds the store paths mentioned in a text, e.g. in the environment of a runtime
+pub fn find_store_paths(text: &str) -> Vec<String> {
+    let mut store_paths = BTreeSet::new();
+    for (start, prefix) in text.match_indices(STORE_DIR) {
+        let rest = &text[start + prefix.len()..];
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "+-._?=".contains(c)))
+            .unwrap_or(rest.len());
+        let name = &rest[..end];
+        // The hash part of a store path is 32 characters long
+        if name.len() > 33 && name.as_bytes()[32] == b'-' {
+            store_paths.insert(format!("{STORE_DIR}{name}"));
+        }
+    }
+    store_paths.into_iter().collect()
+}
+
+/// Copies the environment of a runtime to the cache configured by the operator, if any
+pub async fn upload_to_cache(runtime_dir: &str, timeout: WholeSeconds) -> Result<(), Error> {
+    let Some(cache_upload_url) = NIX_SETTINGS
+        .get()
+        .and_then(|settings| settings.cache_upload_url.as_ref())
+    else {
+        return Ok(());
+    };
+    let env_path = format!("{runtime_dir}/{ENV_FILE}");
+    let env = fs::read_to_string(&env_path)
+        .await
+        .map_err(|e| anyhow!("Failed to read {env_path}\nError: {e}"))?;
+    let store_paths = find_store_paths(&env);
+    if store_paths.is_empty() {
+        return Ok(());
+    }
+    get_stdout_with_timeout(
+        command("nix")
+            .args(FLAKE_FEATURES)
+            .args(["copy", "--to", cache_upload_url])
+            .args(store_paths),
+        "nix copy",
+        timeout,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Returns the store paths and everything they depend on at runtime
+pub async fn get_closure(store_paths: &[String]) -> Result<Vec<String>, Error> {
+    if store_paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    let requisites = get_stdout_with_timeout(
+        command("nix-store")
+            .args(["--query", "--requisites"])
+            .args(store_paths),
+        "nix-store --query --requisites",
+        QUERY_TIMEOUT,
+    )
+    .await?;
+    Ok(requisites.lines().map(|line| line.to_string()).collect())
+}
+
+fn parse_path_sizes(output: &str) -> Result<BTreeMap<String, u64>, Error> {
+    output
+        .lines()
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next().map(str::parse)) {
+                (Some(path), Some(Ok(size))) => Ok((path.to_string(), size)),
+                _ => Err(anyhow!(
+                    "Failed to parse the output of nix path-info: {line}"
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Returns the NAR size of each of the store paths in bytes
+pub async fn get_path_sizes(store_paths: &[String]) -> Result<BTreeMap<String, u64>, Error> {
+    if store_paths.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let output = get_stdout_with_timeout(
+        command("nix")
+            .args(FLAKE_FEATURES)
+            .args(["path-info", "--size"])
+            .args(store_paths),
+        "nix path-info --size",
+        QUERY_TIMEOUT,
+    )
+    .await?;
+    parse_path_sizes(&output)
+}
+
+/// Returns the size of every valid path in the store in bytes
+pub async fn get_store_size() -> Result<u64, Error> {
+    let output = get_stdout_with_timeout(
+        command("nix")
+            .args(FLAKE_FEATURES)
+            .args(["path-info", "--all", "--size"]),
+        "nix path-info --all --size",
+        QUERY_TIMEOUT,
+    )
+    .await?;
+    Ok(parse_path_sizes(&output)?.values().sum())
+}
+
+/// Writes the closure of the store paths to `output_path` in the format of `nix-store --export`
+pub async fn export_closure(
+    store_paths: &[String],
+    output_path: &str,
+    timeout: WholeSeconds,
+) -> Result<(), Error> {
+    let requisites = get_closure(store_paths).await?;
+    let output = fs::File::create(output_path)
+        .await
+        .map_err(|e| anyhow!("Failed to create {output_path}\nError: {e}"))?
+        .into_std()
+        .await;
+    get_stdout_with_timeout(
+        command("nix-store")
+            .arg("--export")
+            .args(requisites)
+            .stdout(output),
+        "nix-store --export",
+        timeout,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Imports a closure written by `nix-store --export`, no network access is needed
+pub async fn import_closure(input_path: &str, timeout: WholeSeconds) -> Result<(), Error> {
+    let input = fs::File::open(input_path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {input_path}\nError: {e}"))?
+        .into_std()
+        .await;
+    get_stdout_with_timeout(
+        command("nix-store").arg("--import").stdin(input),
+        "nix-store --import",
+        timeout,
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Generation {
+    pub generation: u32,
+    pub created_at: String,
+    pub current: bool,
+}
+
+/// Parses the lines of `nix-env --list-generations`, e.g. "   3   2024-06-12 10:00:00   (current)"
+fn parse_generations(output: &str) -> Vec<Generation> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let generation = fields.next()?.parse().ok()?;
+            let date = fields.next()?;
+            let time = fields.next()?;
+            Some(Generation {
+                generation,
+                created_at: format!("{date} {time}"),
+                current: fields.next() == Some("(current)"),
+            })
+        })
+        .collect()
+}
+
+/// Lists the generations of the profile that Nix itself is installed in
+pub async fn list_generations() -> Result<Vec<Generation>, Error> {
+    let output = get_stdout(
+        command("nix-env").arg("--list-generations"),
+        "nix-env --list-generations",
+    )
+    .await?;
+    Ok(parse_generations(&output))
+}
+
+pub async fn get_current_generation() -> Result<Option<u32>, Error> {
+    Ok(list_generations()
+        .await?
+        .into_iter()
+        .find(|generation| generation.current)
+        .map(|generation| generation.generation))
+}
+
+/// Returns the version of the installed Nix, e.g. "2.23.1"
+pub async fn get_version() -> Result<String, Error> {
+    let output = get_stdout(command("nix-env").arg("--version"), "nix-env --version").await?;
+    output
+        .split_whitespace()
+        .last()
+        .map(|version| version.to_string())
+        .ok_or_else(|| anyhow!("Failed to parse the output of nix-env --version: {output}"))
+}
+
+#[derive(Serialize)]
+pub struct GarbageCollectionResult {
+    pub deleted_paths: u64,
+    pub freed_bytes: u64,
+}
+
+/// Parses the summary line of `nix-store --gc`, e.g. "12 store paths deleted, 34.56 MiB freed"
+fn parse_gc_summary(output: &str) -> Option<GarbageCollectionResult> {
+    let line = output
+        .lines()
+        .rev()
+        .find(|line| line.contains("store paths deleted"))?;
+    let (deleted, freed) = line.split_once(',')?;
+    let deleted_paths = deleted.split_whitespace().next()?.parse().ok()?;
+    let mut freed = freed.split_whitespace();
+    let amount: f64 = freed.next()?.parse().ok()?;
+    let multiplier = match freed.next()? {
+        "bytes" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some(GarbageCollectionResult {
+        deleted_paths,
+        freed_bytes: (amount * multiplier) as u64,
+    })
+}
+
+pub async fn collect_garbage(timeout: WholeSeconds) -> Result<GarbageCollectionResult, Error> {
+    let _gc_guard = GC_LOCK.write().await;
+    let res = time::timeout(
+        Duration::from_secs(timeout.into()),
+        command("nix-store").arg("--gc").kill_on_drop(true).output(),
+    )
+    .await
+    .map_err(|_| anyhow!("nix-store --gc timed out after {timeout} second(s)"))?
+    .map_err(|e| anyhow!("Failed to run nix-store --gc: {e}"))?;
+    let stdout = String::from_utf8_lossy(&res.stdout);
+    let stderr = String::from_utf8_lossy(&res.stderr);
+    if !res.status.success() {
+        return Err(anyhow!(
+            "nix-store --gc failed with\nstderr: {stderr}\nstdout: {stdout}"
+        ));
+    }
+    parse_gc_summary(&stderr)
+        .or_else(|| parse_gc_summary(&stdout))
+        .ok_or_else(|| {
+            anyhow!(
+                "Failed to parse the output of nix-store --gc\nstderr: {stderr}\nstdout: {stdout}"
+            )
+        })
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashSet},
+    io,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Error};
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio::{fs, task, time};
+
+use crate::{
+    box_id::{BoxId, BoxIdAllocator},
+    cleanup::cleanup_box,
+    globals::{
+        DB_PATH, ISOLATE_BOXES_DIR, ISOLATE_CGROUP_DIR, RUNTIMES_DIR, STAGING_DIR, TEMP_DIR,
+    },
+    runtime_usage::{DrainGuard, RuntimeUsage},
+    types::WholeSeconds,
+};
+
+#[derive(Serialize, Default)]
+pub struct ReconciliationReport {
+    pub dry_run: bool,
+    pub boxes: Vec<u64>,
+    pub cgroups: Vec<String>,
+    pub temp_entries: Vec<String>,
+    pub staging_dirs: Vec<String>,
+    pub orphan_runtime_dirs: Vec<String>,
+    pub orphan_version_dirs: Vec<String>,
+    pub runtimes_without_dir: Vec<u32>,
+    pub errors: Vec<String>,
+}
+
+impl ReconciliationReport {
+    fn is_empty(&self) -> bool {
+        self.boxes.is_empty()
+            && self.cgroups.is_empty()
+            && self.temp_entries.is_empty()
+            && self.staging_dirs.is_empty()
+            && self.orphan_runtime_dirs.is_empty()
+            && self.orphan_version_dirs.is_empty()
+            && self.runtimes_without_dir.is_empty()
+            && self.errors.is_empty()
+    }
+}
+
+async fn list_dir(path: &str) -> Result<Vec<String>, Error> {
+    let mut entries = match fs::read_dir(path).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("Failed to read {path}\nError: {e}")),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| anyhow!("Failed to read an entry of {path}\nError: {e}"))?
+    {
+        names.push(entry.file_name().to_string_lossy().to_string());
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Temporary entries are named `<box id>-metadata.txt` or `<box id>-submission`
+fn get_temp_entry_box_id(name: &str) -> Option<u64> {
+    let (id, suffix) = name.split_once('-')?;
+    if suffix != "metadata.txt" && suffix != "submission" {
+        return None;
+    }
+    id.parse().ok()
+}
+
+struct StoredRuntimes {
+    ids: HashSet<u32>,
+    versions: HashSet<(u32, u32)>,
+}
+
+fn get_stored_runtimes() -> Result<StoredRuntimes, Error> {
+    let connection =
+        Connection::open(DB_PATH).map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+    let mut stmt = connection
+        .prepare("SELECT id FROM runtime")
+        .map_err(|e| anyhow!("Failed to prepare SQL statement: {e}"))?;
+    let ids = stmt
+        .query_map([], |row| row.get(0))
+        .map_err(|e| anyhow!("Failed to get runtime ids: {e}"))?
+        .collect::<Result<HashSet<u32>, _>>()
+        .map_err(|e| anyhow!("Failed to get runtime id from the row: {e}"))?;
+    let mut stmt = connection
+        .prepare("SELECT runtime_id, version FROM runtime_version")
+        .map_err(|e| anyhow!("Failed to prepare SQL statement: {e}"))?;
+    let versions = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| anyhow!("Failed to get runtime versions: {e}"))?
+        .collect::<Result<HashSet<(u32, u32)>, _>>()
+        .map_err(|e| anyhow!("Failed to get runtime version from the row: {e}"))?;
+    Ok(StoredRuntimes { ids, versions })
+}
+
+async fn reconcile_boxes(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    report: &mut ReconciliationReport,
+) -> Result<(), Error> {
+    // Only boxes whose IDs can be reserved are residual, the others are in use
+    let mut reserved: BTreeMap<u64, BoxId> = BTreeMap::new();
+    let mut reserve = |id: u64| {
+        if reserved.contains_key(&id) {
+            return true;
+        }
+        match box_id_allocator.try_reserve(id) {
+            Some(box_id) => {
+                reserved.insert(id, box_id);
+                true
+            }
+            None => false,
+        }
+    };
+
+    for name in list_dir(ISOLATE_BOXES_DIR).await? {
+        if let Ok(id) = name.parse() {
+            if reserve(id) {
+                report.boxes.push(id);
+            }
+        }
+    }
+    for name in list_dir(ISOLATE_CGROUP_DIR).await? {
+        if let Some(id) = name.strip_prefix("box-").and_then(|id| id.parse().ok()) {
+            if reserve(id) {
+                report.cgroups.push(name);
+            }
+        }
+    }
+    // Entries that aren't named after a box are left alone, they may not be ours
+    for name in list_dir(TEMP_DIR).await? {
+        if get_temp_entry_box_id(&name).is_some_and(&mut reserve) {
+            report.temp_entries.push(name);
+        }
+    }
+    // Staging directories are named after the box ID reserved by their installation
+    for name in list_dir(STAGING_DIR).await? {
+        if name.parse().is_ok_and(&mut reserve) {
+            report.staging_dirs.push(name);
+        }
+    }
+
+    if report.dry_run {
+        return Ok(());
+    }
+
+    for id in reserved.keys() {
+        if let Err(e) = cleanup_box(*id, &format!("{TEMP_DIR}/{id}-metadata.txt")).await {
+            report
+                .errors
+                .push(format!("Failed to clean up box {id}: {e}"));
+        }
+    }
+    // `isolate --cleanup` leaves the cgroup behind if the box directory is already gone
+    for name in &report.cgroups {
+        let path = format!("{ISOLATE_CGROUP_DIR}/{name}");
+        if let Err(e) = fs::remove_dir(&path).await {
+            if e.kind() != io::ErrorKind::NotFound {
+                report.errors.push(format!("Failed to remove {path}: {e}"));
+            }
+        }
+    }
+    for name in &report.temp_entries {
+        let path = format!("{TEMP_DIR}/{name}");
+        let res = if name.ends_with("-metadata.txt") {
+            crate::fs::remove_file_if_exists(&path).await
+        } else {
+            crate::fs::remove_dir_if_exists(&path).await
+        };
+        if let Err(e) = res {
+            report.errors.push(e.to_string());
+        }
+    }
+    for name in &report.staging_dirs {
+        if let Err(e) = crate::fs::remove_dir_if_exists(&format!("{STAGING_DIR}/{name}")).await {
+            report.errors.push(e.to_string());
+        }
+    }
+    // The reserved box IDs are released here, after everything has been cleaned up
+    drop(reserved);
+    Ok(())
+}
+
+async fn reconcile_runtime_dirs(
+    runtime_usage: &Arc<RuntimeUsage>,
+    report: &mut ReconciliationReport,
+) -> Result<(), Error> {
+    // Installations insert the runtime row before moving its directory into place, so the
+    // directories must be listed before the rows to not mistake a new runtime for an orphan
+    let names = list_dir(RUNTIMES_DIR).await?;
+    let mut version_dirs = Vec::new();
+    for name in &names {
+        if let Ok(id) = name.parse::<u32>() {
+            for version in list_dir(&format!("{RUNTIMES_DIR}/{name}")).await? {
+                if let Ok(version) = version.parse::<u32>() {
+                    version_dirs.push((id, version));
+                }
+            }
+        }
+    }
+    let stored_runtimes = task::spawn_blocking(get_stored_runtimes)
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+
+    // Runtimes being deleted are drained until their directories are removed, so only the
+    // directories of runtimes that can be drained are residual, like with box IDs
+    let mut drained: BTreeMap<u32, DrainGuard> = BTreeMap::new();
+    for name in names {
+        // Directories of runtimes being deleted are moved to `<id>.deleting` first
+        let id = name.strip_suffix(".deleting").unwrap_or(&name);
+        if let Ok(id) = id.parse::<u32>() {
+            if stored_runtimes.ids.contains(&id) {
+                continue;
+            }
+            if let Entry::Vacant(entry) = drained.entry(id) {
+                match runtime_usage.try_drain(id)? {
+                    Some(guard) => {
+                        entry.insert(guard);
+                    }
+                    None => continue,
+                }
+            }
+            report.orphan_runtime_dirs.push(name);
+        }
+    }
+    // Runtimes without any version haven't finished migrating yet, their files are in `<id>/1`
+    // until the migration is resumed at the next startup
+    let versioned_ids: HashSet<u32> = stored_runtimes.versions.iter().map(|(id, _)| *id).collect();
+    // Versions are inserted before their directory is moved into place
+    for (id, version) in version_dirs {
+        if versioned_ids.contains(&id) && !stored_runtimes.versions.contains(&(id, version)) {
+            report.orphan_version_dirs.push(format!("{id}/{version}"));
+        }
+    }
+    for id in &stored_runtimes.ids {
+        let path = format!("{RUNTIMES_DIR}/{id}");
+        if !fs::try_exists(&path)
+            .await
+            .map_err(|e| anyhow!("Failed to check if {path} exists\nError: {e}"))?
+        {
+            report.runtimes_without_dir.push(*id);
+        }
+    }
+    report.runtimes_without_dir.sort();
+
+    if report.dry_run {
+        return Ok(());
+    }
+
+    for name in report
+        .orphan_runtime_dirs
+        .iter()
+        .chain(&report.orphan_version_dirs)
+    {
+        if let Err(e) = crate::fs::remove_dir_if_exists(&format!("{RUNTIMES_DIR}/{name}")).await {
+            report.errors.push(e.to_string());
+        }
+    }
+    drop(drained);
+    Ok(())
+}
+
+/// Finds (and unless `dry_run` is set, removes) the boxes, cgroups, temporary files and runtime
+/// directories that are not referenced by any running execution or deletion or by the `runtime` table
+pub async fn reconcile(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    runtime_usage: &Arc<RuntimeUsage>,
+    dry_run: bool,
+) -> Result<ReconciliationReport, Error> {
+    let mut report = ReconciliationReport {
+        dry_run,
+        ..Default::default()
+    };
+    reconcile_boxes(box_id_allocator, &mut report).await?;
+    reconcile_runtime_dirs(runtime_usage, &mut report).await?;
+    Ok(report)
+}
+
+pub async fn reconcile_and_log(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    runtime_usage: &Arc<RuntimeUsage>,
+) {
+    match reconcile(box_id_allocator, runtime_usage, false).await {
+        Ok(report) => {
+            if !report.is_empty() {
+                eprintln!(
+                    "Reconciled residual files: {}",
+                    serde_json::to_string(&report).unwrap_or_default()
+                );
+            }
+        }
+        Err(e) => eprintln!("Failed to reconcile residual files: {e}"),
+    }
+}
+
+pub fn start_reconciliation_loop(
+    interval: WholeSeconds,
+    box_id_allocator: Arc<BoxIdAllocator>,
+    runtime_usage: Arc<RuntimeUsage>,
+) {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(interval.into());
+        let mut interval = time::interval_at(time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            reconcile_and_log(&box_id_allocator, &runtime_usage).await;
+        }
+    });
+}
@@ -0,0 +1,281 @@
+use std::{collections::BTreeMap, io};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, task};
+
+use crate::{globals::RUNTIMES_DIR, nix};
+
+/// The environment of a runtime as captured from its Nix shell
+pub const ENV_FILE: &str = "env.json";
+/// The output of `env` that runtimes installed before `env.json` have
+pub const LEGACY_ENV_FILE: &str = "env";
+
+pub const ENV_SETTINGS_FILE: &str = "env_settings.json";
+
+pub type Env = BTreeMap<String, String>;
+
+/// Variables that only make sense while Nix builds the shell
+const DEFAULT_DENY: [&str; 16] = [
+    "NIX_BUILD_TOP",
+    "NIX_BUILD_CORES",
+    "NIX_LOG_FD",
+    "NIX_STORE",
+    "TMPDIR",
+    "TEMPDIR",
+    "TMP",
+    "TEMP",
+    "out",
+    "outputs",
+    "builder",
+    "stdenv",
+    "phases",
+    "*Phase",
+    "shellHook",
+    "initialPath",
+];
+
+fn default_deny() -> Vec<String> {
+    DEFAULT_DENY
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect()
+}
+
+/// Which variables of the captured environment reach submissions.
+/// Patterns match whole variable names and `*` matches any sequence of characters
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct EnvSettings {
+    /// Only variables matching one of these patterns are kept, all of them are if it's empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Variables matching one of these patterns are dropped even if they are allowed
+    #[serde(default = "default_deny")]
+    pub deny: Vec<String>,
+    /// Set on top of the captured environment
+    #[serde(default)]
+    pub extra: Env,
+}
+
+impl Default for EnvSettings {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            deny: default_deny(),
+            extra: Env::new(),
+        }
+    }
+}
+
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` in the pattern
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl EnvSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        for pattern in self.allow.iter().chain(&self.deny) {
+            if pattern.is_empty()
+                || !pattern
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '*')
+            {
+                return Err(anyhow!("Invalid environment variable pattern: {pattern}"));
+            }
+        }
+        for (key, value) in &self.extra {
+            if !is_variable_name(key) {
+                return Err(anyhow!("Invalid environment variable name: {key}"));
+            }
+            if value.contains('\0') {
+                return Err(anyhow!("The value of {key} can't contain a NUL byte"));
+            }
+        }
+        Ok(())
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        (self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|pattern| matches_pattern(pattern, name)))
+            && !self
+                .deny
+                .iter()
+                .any(|pattern| matches_pattern(pattern, name))
+    }
+
+    /// Drops the variables that aren't allowed and adds the extra ones
+    pub fn apply(&self, env: Env) -> Env {
+        env.into_iter()
+            .filter(|(key, _)| self.is_allowed(key))
+            .chain(self.extra.clone())
+            .collect()
+    }
+}
+
+pub async fn write_env_settings(runtime_dir: &str, settings: &EnvSettings) -> Result<(), Error> {
+    let settings_path = format!("{runtime_dir}/{ENV_SETTINGS_FILE}");
+    let settings = serde_json::to_string(settings)
+        .map_err(|e| anyhow!("Failed to serialize environment settings: {e}"))?;
+    fs::write(&settings_path, settings)
+        .await
+        .map_err(|e| anyhow!("Failed to write {settings_path}\nError: {e}"))
+}
+
+/// Runtimes installed before environment settings use the default ones
+pub async fn read_env_settings(runtime_dir: &str) -> Result<EnvSettings, Error> {
+    let settings_path = format!("{runtime_dir}/{ENV_SETTINGS_FILE}");
+    match fs::read_to_string(&settings_path).await {
+        Ok(settings) => serde_json::from_str(&settings)
+            .map_err(|e| anyhow!("Failed to parse {settings_path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(EnvSettings::default()),
+        Err(e) => Err(anyhow!("Failed to read {settings_path}\nError: {e}")),
+    }
+}
+
+/// Parses the output of `env -0`, where every entry ends with a NUL byte
+/// and only the first `=` separates the name from the value
+pub fn parse_env_output(output: &[u8]) -> Result<Env, Error> {
+    let mut env = Env::new();
+    for entry in output.split(|byte| *byte == 0) {
+        if entry.is_empty() {
+            continue;
+        }
+        let entry = String::from_utf8_lossy(entry);
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Found an environment entry without a value: {entry}"))?;
+        env.insert(key.to_string(), value.to_string());
+    }
+    Ok(env)
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Best effort parsing of the output of `env`, a line starts a new variable
+/// if what comes before its first `=` is a variable name, otherwise it continues the previous value
+fn parse_legacy_env(env: &str) -> Env {
+    let mut vars = Env::new();
+    let mut current: Option<(String, String)> = None;
+    for line in env.lines() {
+        match line.split_once('=') {
+            Some((key, value)) if is_variable_name(key) => {
+                if let Some((key, value)) = current.take() {
+                    vars.insert(key, value);
+                }
+                current = Some((key.to_string(), value.to_string()));
+            }
+            _ => {
+                if let Some((_, value)) = current.as_mut() {
+                    value.push('\n');
+                    value.push_str(line);
+                }
+            }
+        }
+    }
+    if let Some((key, value)) = current {
+        vars.insert(key, value);
+    }
+    vars
+}
+
+pub fn find_store_paths(env: &Env) -> Vec<String> {
+    let values: Vec<&str> = env.values().map(String::as_str).collect();
+    nix::find_store_paths(&values.join("\n"))
+}
+
+pub async fn write_env(runtime_dir: &str, env: &Env) -> Result<(), Error> {
+    let env_path = format!("{runtime_dir}/{ENV_FILE}");
+    let env =
+        serde_json::to_string(env).map_err(|e| anyhow!("Failed to serialize environment: {e}"))?;
+    fs::write(&env_path, env)
+        .await
+        .map_err(|e| anyhow!("Failed to write {env_path}\nError: {e}"))
+}
+
+/// Reads `env.json` of a runtime, falling back to the legacy `env` file
+pub async fn read_env(runtime_dir: &str) -> Result<Env, Error> {
+    let env_path = format!("{runtime_dir}/{ENV_FILE}");
+    match fs::read_to_string(&env_path).await {
+        Ok(env) => serde_json::from_str(&env)
+            .map_err(|e| anyhow!("Failed to parse {env_path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let legacy_env_path = format!("{runtime_dir}/{LEGACY_ENV_FILE}");
+            let env = fs::read_to_string(&legacy_env_path).await.map_err(|e| {
+                anyhow!("Failed to read environment variables from: {legacy_env_path}: {e}")
+            })?;
+            task::spawn_blocking(move || parse_legacy_env(&env))
+                .await
+                .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))
+        }
+        Err(e) => Err(anyhow!("Failed to read {env_path}\nError: {e}")),
+    }
+}
+
+/// The environment submissions of a runtime run with, the settings are applied again
+/// so environments captured before they existed are filtered too
+pub async fn read_submission_env(runtime_dir: &str) -> Result<Env, Error> {
+    let env = read_env(runtime_dir).await?;
+    Ok(read_env_settings(runtime_dir).await?.apply(env))
+}
+
+async fn migrate_env(version_dir: &str) -> Result<(), Error> {
+    let env = read_env(version_dir).await?;
+    write_env(version_dir, &env).await?;
+    let legacy_env_path = format!("{version_dir}/{LEGACY_ENV_FILE}");
+    fs::remove_file(&legacy_env_path)
+        .await
+        .map_err(|e| anyhow!("Failed to remove {legacy_env_path}\nError: {e}"))
+}
+
+/// Converts the legacy `env` files of every runtime version to `env.json`,
+/// versions that fail to migrate are logged and keep their legacy file
+pub async fn migrate_legacy_env_files() -> Result<(), Error> {
+    if !fs::try_exists(RUNTIMES_DIR)
+        .await
+        .map_err(|e| anyhow!("Failed to check if {RUNTIMES_DIR} exists: {e}"))?
+    {
+        return Ok(());
+    }
+    for runtime_dir in crate::fs::list_numbered_dirs(RUNTIMES_DIR).await? {
+        for version_dir in crate::fs::list_numbered_dirs(&runtime_dir).await? {
+            let has_legacy_env = fs::try_exists(format!("{version_dir}/{LEGACY_ENV_FILE}"))
+                .await
+                .unwrap_or(false);
+            let has_env = fs::try_exists(format!("{version_dir}/{ENV_FILE}"))
+                .await
+                .unwrap_or(false);
+            if !has_legacy_env || has_env {
+                continue;
+            }
+            eprintln!("Migrating the environment of {version_dir} to {ENV_FILE}");
+            if let Err(e) = migrate_env(&version_dir).await {
+                eprintln!("Failed to migrate the environment of {version_dir}\nError: {e}");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::{io, sync::Arc};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    api::execution::{run_submission, ExecutionResponse},
+    box_id::BoxIdAllocator,
+    cleanup::Cleaner,
+    limits::SystemLimits,
+    types::Runtime,
+};
+
+/// A program that must produce `expected_output` for the runtime to be considered working
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct RuntimeTest {
+    pub source_code: String,
+    pub input: Option<String>,
+    pub expected_output: String,
+}
+
+#[derive(Serialize)]
+pub struct TestResult {
+    pub passed: bool,
+    pub expected_output: String,
+    pub result: ExecutionResponse,
+}
+
+#[derive(Serialize)]
+pub struct TestReport {
+    pub passed: bool,
+    pub tests: Vec<TestResult>,
+}
+
+pub async fn read_tests(runtime_dir: &str) -> Result<Vec<RuntimeTest>, Error> {
+    let tests_path = format!("{runtime_dir}/tests.json");
+    match fs::read_to_string(&tests_path).await {
+        Ok(tests) => serde_json::from_str(&tests)
+            .map_err(|e| anyhow!("Failed to parse {tests_path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(anyhow!("Failed to read {tests_path}\nError: {e}")),
+    }
+}
+
+pub async fn write_tests(runtime_dir: &str, tests: &[RuntimeTest]) -> Result<(), Error> {
+    let tests_path = format!("{runtime_dir}/tests.json");
+    let tests =
+        serde_json::to_string(tests).map_err(|e| anyhow!("Failed to serialize tests: {e}"))?;
+    fs::write(&tests_path, tests)
+        .await
+        .map_err(|e| anyhow!("Failed to write {tests_path}\nError: {e}"))
+}
+
+/// Trailing whitespace is ignored
+fn is_expected_output(result: &ExecutionResponse, expected_output: &str) -> bool {
+    match &result.run {
+        Some(run) => run.stdout.trim_end() == expected_output.trim_end(),
+        None => false,
+    }
+}
+
+/// Runs the tests one after the other with the limits of the runtime
+pub async fn run_tests(
+    box_id_allocator: &Arc<BoxIdAllocator>,
+    cleaner: &Arc<Cleaner>,
+    runtime_id: u32,
+    runtime: &Runtime,
+    tests: &[RuntimeTest],
+    system_limits: &SystemLimits,
+) -> Result<TestReport, Error> {
+    let limits = runtime.limits.get(system_limits)?;
+    let mut results = Vec::new();
+    for (i, test) in tests.iter().enumerate() {
+        let result = run_submission(
+            box_id_allocator,
+            cleaner,
+            runtime_id,
+            runtime,
+            test.source_code.clone(),
+            test.input.clone(),
+            false,
+            &limits.compile,
+            &limits.run,
+        )
+        .await
+        .map_err(|e| anyhow!("Failed to run test {}: {e}", i + 1))?;
+        results.push(TestResult {
+            passed: is_expected_output(&result, &test.expected_output),
+            expected_output: test.expected_output.clone(),
+            result,
+        });
+    }
+    Ok(TestReport {
+        passed: results.iter().all(|result| result.passed),
+        tests: results,
+    })
+}
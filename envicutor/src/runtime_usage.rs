@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use tokio::{sync::Notify, time};
+
+#[derive(Default)]
+struct UsageEntry {
+    in_flight: usize,
+    draining: bool,
+}
+
+#[derive(Default, Clone)]
+struct ExecutionStats {
+    total_executions: u64,
+    last_executed_at: Option<u64>,
+}
+
+/// Usage of a runtime since the server started
+#[derive(Serialize)]
+pub struct UsageStats {
+    pub in_flight: usize,
+    pub total_executions: u64,
+    /// Unix timestamp in seconds
+    pub last_executed_at: Option<u64>,
+}
+
+/// Counts the executions that are using each runtime, so a runtime can be drained before deletion
+pub struct RuntimeUsage {
+    entries: Mutex<HashMap<u32, UsageEntry>>,
+    released: Notify,
+    executions: Mutex<HashMap<u32, ExecutionStats>>,
+}
+
+/// Held by an execution for as long as it uses the runtime
+pub struct RuntimeLease {
+    id: u32,
+    usage: Arc<RuntimeUsage>,
+}
+
+/// Held while a runtime is being deleted, new executions of the runtime are rejected until dropped
+pub struct DrainGuard {
+    id: u32,
+    usage: Arc<RuntimeUsage>,
+}
+
+pub enum DrainError {
+    AlreadyDraining,
+    Timeout(usize),
+    Internal(Error),
+}
+
+impl RuntimeUsage {
+    pub fn new() -> Arc<Self> {
+        Arc::new(RuntimeUsage {
+            entries: Mutex::new(HashMap::new()),
+            released: Notify::new(),
+            executions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns `None` if the runtime is being drained
+    pub fn acquire(self: &Arc<Self>, id: u32) -> Result<Option<RuntimeLease>, Error> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock runtime usage: {e}"))?;
+        let entry = entries.entry(id).or_default();
+        if entry.draining {
+            return Ok(None);
+        }
+        entry.in_flight += 1;
+        Ok(Some(RuntimeLease {
+            id,
+            usage: self.clone(),
+        }))
+    }
+
+    fn in_flight(&self, id: u32) -> Result<usize, Error> {
+        let entries = self
+            .entries
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock runtime usage: {e}"))?;
+        Ok(entries.get(&id).map_or(0, |entry| entry.in_flight))
+    }
+
+    pub fn record_execution(&self, id: u32) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| anyhow!("Failed to get the current time: {e}"))?
+            .as_secs();
+        let mut executions = self
+            .executions
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock runtime executions: {e}"))?;
+        let stats = executions.entry(id).or_default();
+        stats.total_executions += 1;
+        stats.last_executed_at = Some(now);
+        Ok(())
+    }
+
+    pub fn stats(&self, id: u32) -> Result<UsageStats, Error> {
+        let in_flight = self.in_flight(id)?;
+        let executions = self
+            .executions
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock runtime executions: {e}"))?
+            .get(&id)
+            .cloned()
+            .unwrap_or_default();
+        Ok(UsageStats {
+            in_flight,
+            total_executions: executions.total_executions,
+            last_executed_at: executions.last_executed_at,
+        })
+    }
+
+    /// Rejects new executions of the runtime and waits until the running ones finish
+    pub async fn drain(
+        self: &Arc<Self>,
+        id: u32,
+        timeout: Duration,
+    ) -> Result<DrainGuard, DrainError> {
+        {
+            let mut entries = self
+                .entries
+                .lock()
+                .map_err(|e| DrainError::Internal(anyhow!("Failed to lock runtime usage: {e}")))?;
+            let entry = entries.entry(id).or_default();
+            if entry.draining {
+                return Err(DrainError::AlreadyDraining);
+            }
+            entry.draining = true;
+        }
+        // From here on, dropping the guard stops the draining
+        let guard = DrainGuard {
+            id,
+            usage: self.clone(),
+        };
+
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let released = self.released.notified();
+            let in_flight = self.in_flight(id).map_err(DrainError::Internal)?;
+            if in_flight == 0 {
+                return Ok(guard);
+            }
+            if time::timeout_at(deadline, released).await.is_err() {
+                return Err(DrainError::Timeout(in_flight));
+            }
+        }
+    }
+
+    /// Drains a runtime that isn't used, returns `None` if it's in use or already being drained
+    pub fn try_drain(self: &Arc<Self>, id: u32) -> Result<Option<DrainGuard>, Error> {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock runtime usage: {e}"))?;
+        let entry = entries.entry(id).or_default();
+        if entry.draining || entry.in_flight > 0 {
+            return Ok(None);
+        }
+        entry.draining = true;
+        Ok(Some(DrainGuard {
+            id,
+            usage: self.clone(),
+        }))
+    }
+
+    fn update(&self, id: u32, update_fn: impl FnOnce(&mut UsageEntry)) {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Failed to lock runtime usage: {e}");
+                return;
+            }
+        };
+        if let Some(entry) = entries.get_mut(&id) {
+            update_fn(entry);
+            if entry.in_flight == 0 && !entry.draining {
+                entries.remove(&id);
+            }
+        }
+    }
+}
+
+impl Drop for RuntimeLease {
+    fn drop(&mut self) {
+        self.usage.update(self.id, |entry| {
+            entry.in_flight = entry.in_flight.saturating_sub(1)
+        });
+        self.usage.released.notify_waiters();
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        self.usage.update(self.id, |entry| entry.draining = false);
+    }
+}
@@ -0,0 +1,217 @@
+use std::{io, path::Path};
+
+use anyhow::{anyhow, Error};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::{fs, task};
+
+use crate::{
+    globals::{DB_PATH, RUNTIMES_DIR},
+    limits::RuntimeLimits,
+    nix,
+    types::{Runtime, WholeSeconds},
+};
+
+#[derive(Serialize)]
+pub struct RuntimeVersion {
+    pub version: u32,
+    pub source_file_name: String,
+    pub is_compiled: bool,
+    pub is_active: bool,
+    pub created_at: String,
+}
+
+/// Every version of a runtime is immutable and lives in its own directory
+pub fn get_version_dir(id: u32, version: u32) -> String {
+    format!("{RUNTIMES_DIR}/{id}/{version}")
+}
+
+pub fn is_compiled(id: u32, version: u32) -> Result<bool, Error> {
+    let compile_script_path = format!("{}/compile", get_version_dir(id, version));
+    Path::new(&compile_script_path)
+        .try_exists()
+        .map_err(|e| anyhow!("Could not check if {compile_script_path} exists: {e}"))
+}
+
+/// Runtimes installed before per-runtime limits only use the system limits
+pub fn read_limits(id: u32, version: u32) -> Result<RuntimeLimits, Error> {
+    let limits_path = format!("{}/limits.json", get_version_dir(id, version));
+    match std::fs::read_to_string(&limits_path) {
+        Ok(limits) => serde_json::from_str(&limits)
+            .map_err(|e| anyhow!("Failed to parse {limits_path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(RuntimeLimits::default()),
+        Err(e) => Err(anyhow!("Failed to read {limits_path}\nError: {e}")),
+    }
+}
+
+pub fn list_versions(connection: &Connection, id: u32) -> Result<Vec<RuntimeVersion>, Error> {
+    let mut stmt = connection
+        .prepare(
+            "SELECT version, source_file_name, is_active, created_at FROM runtime_version WHERE runtime_id = ? ORDER BY version",
+        )
+        .map_err(|e| anyhow!("Failed to prepare SQL statement: {e}"))?;
+    let rows = stmt
+        .query_map([id], |row| {
+            Ok((
+                row.get::<_, u32>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, bool>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })
+        .map_err(|e| anyhow!("Failed to get versions of runtime with id: {id}\nError: {e}"))?;
+    let mut versions = Vec::new();
+    for row in rows {
+        let (version, source_file_name, is_active, created_at) =
+            row.map_err(|e| anyhow!("Failed to get runtime version from the row: {e}"))?;
+        versions.push(RuntimeVersion {
+            version,
+            source_file_name,
+            is_compiled: is_compiled(id, version)?,
+            is_active,
+            created_at,
+        });
+    }
+    Ok(versions)
+}
+
+pub fn get_next_version(connection: &Connection, id: u32) -> Result<u32, Error> {
+    connection
+        .query_row(
+            "SELECT COALESCE(MAX(version), 0) + 1 FROM runtime_version WHERE runtime_id = ?",
+            [id],
+            |row| row.get(0),
+        )
+        .map_err(|e| anyhow!("Failed to get the next version of runtime with id: {id}\nError: {e}"))
+}
+
+/// Makes `version` the active version of the runtime, it must already exist
+pub fn activate_version(connection: &Connection, id: u32, version: u32) -> Result<(), Error> {
+    let trx = connection
+        .unchecked_transaction()
+        .map_err(|e| anyhow!("Failed to start SQLite transaction: {e}"))?;
+    trx.execute(
+        "UPDATE runtime_version SET is_active = (version = ?) WHERE runtime_id = ?",
+        (version, id),
+    )
+    .map_err(|e| {
+        anyhow!("Failed to activate version {version} of runtime with id: {id}\nError: {e}")
+    })?;
+    trx.execute(
+        "UPDATE runtime SET source_file_name = (SELECT source_file_name FROM runtime_version WHERE runtime_id = ?1 AND version = ?2) WHERE id = ?1",
+        (id, version),
+    )
+    .map_err(|e| anyhow!("Failed to update runtime with id: {id}\nError: {e}"))?;
+    trx.commit()
+        .map_err(|e| anyhow!("Failed to commit SQLite transaction: {e}"))
+}
+
+/// Loads a version of a runtime, keeping the rest of `runtime` (like its name, which isn't versioned) as is,
+/// returns `None` if the version doesn't exist
+pub async fn load_version(
+    id: u32,
+    runtime: Runtime,
+    version: u32,
+) -> Result<Option<Runtime>, Error> {
+    task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        let source_file_name: Option<String> = connection
+            .query_row(
+                "SELECT source_file_name FROM runtime_version WHERE runtime_id = ? AND version = ?",
+                [id, version],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| {
+                anyhow!("Failed to get version {version} of runtime with id: {id}\nError: {e}")
+            })?;
+        match source_file_name {
+            Some(source_file_name) => Ok(Some(Runtime {
+                source_file_name,
+                is_compiled: is_compiled(id, version)?,
+                version,
+                limits: read_limits(id, version)?,
+                ..runtime
+            })),
+            None => Ok(None),
+        }
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))?
+}
+
+fn get_unversioned_runtimes() -> Result<Vec<(u32, String)>, Error> {
+    let connection =
+        Connection::open(DB_PATH).map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+    let mut stmt = connection
+        .prepare(
+            "SELECT id, source_file_name FROM runtime WHERE id NOT IN (SELECT runtime_id FROM runtime_version)",
+        )
+        .map_err(|e| anyhow!("Failed to prepare SQL statement: {e}"))?;
+    let runtimes = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .map_err(|e| anyhow!("Failed to get unversioned runtimes: {e}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Failed to get runtime from the row: {e}"))?;
+    Ok(runtimes)
+}
+
+async fn migrate_runtime(
+    id: u32,
+    source_file_name: String,
+    installation_timeout: WholeSeconds,
+) -> Result<(), Error> {
+    let runtime_dir = format!("{RUNTIMES_DIR}/{id}");
+    let migrating_dir = format!("{runtime_dir}.migrating");
+    let version_dir = get_version_dir(id, 1);
+    // An interrupted migration is resumed from the step it stopped at, the version is only
+    // inserted once everything else is done so the runtime is migrated again until then
+    if !crate::fs::exists(&version_dir).await? {
+        if !crate::fs::exists(&migrating_dir).await? {
+            fs::rename(&runtime_dir, &migrating_dir)
+                .await
+                .map_err(|e| {
+                    anyhow!("Failed to move {runtime_dir} to {migrating_dir}\nError: {e}")
+                })?;
+        }
+        fs::create_dir_all(&runtime_dir)
+            .await
+            .map_err(|e| anyhow!("Failed to create {runtime_dir}\nError: {e}"))?;
+        fs::rename(&migrating_dir, &version_dir)
+            .await
+            .map_err(|e| anyhow!("Failed to move {migrating_dir} to {version_dir}\nError: {e}"))?;
+    }
+
+    // The previous GC roots point into the old location of the files
+    nix::add_gc_roots(&version_dir, installation_timeout).await?;
+
+    task::spawn_blocking(move || {
+        let connection = Connection::open(DB_PATH)
+            .map_err(|e| anyhow!("Failed to open SQLite connection: {e}"))?;
+        connection
+            .execute(
+                "INSERT INTO runtime_version (runtime_id, version, source_file_name, is_active) VALUES (?, 1, ?, TRUE)",
+                (id, &source_file_name),
+            )
+            .map_err(|e| anyhow!("Failed to insert version of runtime with id: {id}\nError: {e}"))
+    })
+    .await
+    .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+    Ok(())
+}
+
+/// Moves the files of runtimes installed before versioning into their first version,
+/// runtimes that fail to migrate are logged and migrated again at the next startup
+pub async fn migrate_unversioned_runtimes(installation_timeout: WholeSeconds) -> Result<(), Error> {
+    let runtimes = task::spawn_blocking(get_unversioned_runtimes)
+        .await
+        .map_err(|e| anyhow!("Failed to spawn blocking task: {e}"))??;
+    for (id, source_file_name) in runtimes {
+        eprintln!("Migrating runtime {id} to version 1");
+        if let Err(e) = migrate_runtime(id, source_file_name, installation_timeout).await {
+            eprintln!("Failed to migrate runtime with id: {id}\nError: {e}");
+        }
+    }
+    Ok(())
+}
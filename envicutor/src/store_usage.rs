@@ -0,0 +1,235 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    closure_mounts::read_closure_mounts,
+    globals::RUNTIMES_DIR,
+    nix,
+    runtime_versions::get_version_dir,
+    types::{Metadata, Runtime},
+};
+
+/// The sizes of the store paths in the closure of a runtime version
+pub const CLOSURE_SIZES_FILE: &str = "closure.json";
+
+#[derive(Deserialize, Serialize)]
+pub struct ClosureSizes {
+    /// The sum of the sizes of the paths in bytes, like `nix path-info -S` for the whole closure
+    pub size: u64,
+    pub paths: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct RuntimeStoreUsage {
+    pub id: u32,
+    pub name: String,
+    /// The closure of the active version
+    pub closure_size: u64,
+    /// The closures of all the versions kept on disk
+    pub size: u64,
+    /// What deleting the runtime and collecting garbage would free
+    pub unique_size: u64,
+    pub shared_size: u64,
+}
+
+#[derive(Serialize)]
+pub struct SharedPath {
+    pub path: String,
+    pub size: u64,
+    pub runtimes: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub struct StoreUsage {
+    pub store_size: u64,
+    /// The union of the closures of all runtimes
+    pub runtimes_size: u64,
+    pub shared_size: u64,
+    /// Sorted by what deleting them would free, largest first
+    pub runtimes: Vec<RuntimeStoreUsage>,
+    /// Sorted by size, largest first
+    pub shared_paths: Vec<SharedPath>,
+}
+
+/// Measures the closure of a runtime version and caches the sizes in its directory
+pub async fn write_closure_sizes(runtime_dir: &str) -> Result<ClosureSizes, Error> {
+    let paths = nix::get_path_sizes(&read_closure_mounts(runtime_dir).await?).await?;
+    let sizes = ClosureSizes {
+        size: paths.values().sum(),
+        paths,
+    };
+    let sizes_json = serde_json::to_string(&sizes)
+        .map_err(|e| anyhow!("Failed to serialize closure sizes: {e}"))?;
+    crate::fs::write_file_atomically(&format!("{runtime_dir}/{CLOSURE_SIZES_FILE}"), &sizes_json)
+        .await?;
+    Ok(sizes)
+}
+
+/// `None` if the sizes of the version haven't been measured yet
+async fn read_cached_closure_sizes(runtime_dir: &str) -> Result<Option<ClosureSizes>, Error> {
+    let sizes_path = format!("{runtime_dir}/{CLOSURE_SIZES_FILE}");
+    match fs::read_to_string(&sizes_path).await {
+        Ok(sizes) => serde_json::from_str(&sizes)
+            .map(Some)
+            .map_err(|e| anyhow!("Failed to parse {sizes_path}\nError: {e}")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read {sizes_path}\nError: {e}")),
+    }
+}
+
+/// Runtimes installed before sizes were recorded get them measured on first use
+pub async fn read_closure_sizes(runtime_dir: &str) -> Result<ClosureSizes, Error> {
+    match read_cached_closure_sizes(runtime_dir).await? {
+        Some(sizes) => Ok(sizes),
+        None => write_closure_sizes(runtime_dir).await,
+    }
+}
+
+/// Runtimes can be deleted while their directories are read, which isn't an error.
+/// `None` if the runtime no longer exists
+async fn unless_deleted<T>(id: u32, res: Result<T, Error>) -> Result<Option<T>, Error> {
+    match res {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => match fs::try_exists(format!("{RUNTIMES_DIR}/{id}")).await {
+            Ok(false) => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+/// The store paths used by any version of the runtime that is kept on disk,
+/// versions that haven't been measured yet are skipped unless `measure` is set
+async fn get_runtime_paths(id: u32, measure: bool) -> Result<BTreeMap<String, u64>, Error> {
+    let mut paths = BTreeMap::new();
+    for version_dir in crate::fs::list_numbered_dirs(&format!("{RUNTIMES_DIR}/{id}")).await? {
+        let sizes = if measure {
+            Some(read_closure_sizes(&version_dir).await?)
+        } else {
+            read_cached_closure_sizes(&version_dir).await?
+        };
+        if let Some(sizes) = sizes {
+            paths.extend(sizes.paths);
+        }
+    }
+    Ok(paths)
+}
+
+/// Runtimes deleted in the meantime are left out
+async fn get_runtimes_paths(
+    metadata: &Metadata,
+    measure: impl Fn(u32) -> bool,
+) -> Result<BTreeMap<u32, BTreeMap<String, u64>>, Error> {
+    let mut runtime_paths = BTreeMap::new();
+    for id in metadata.keys() {
+        let res = get_runtime_paths(*id, measure(*id)).await;
+        if let Some(paths) = unless_deleted(*id, res).await? {
+            runtime_paths.insert(*id, paths);
+        }
+    }
+    Ok(runtime_paths)
+}
+
+/// Every path used by a runtime with its size and the runtimes using it
+fn get_path_users(
+    runtime_paths: &BTreeMap<u32, BTreeMap<String, u64>>,
+) -> BTreeMap<String, (u64, BTreeSet<u32>)> {
+    let mut paths: BTreeMap<String, (u64, BTreeSet<u32>)> = BTreeMap::new();
+    for (id, runtime_path_sizes) in runtime_paths {
+        for (path, size) in runtime_path_sizes {
+            paths
+                .entry(path.clone())
+                .or_insert_with(|| (*size, BTreeSet::new()))
+                .1
+                .insert(*id);
+        }
+    }
+    paths
+}
+
+/// `None` if the runtime was deleted in the meantime
+async fn get_usage(
+    id: u32,
+    runtime: &Runtime,
+    runtime_path_sizes: &BTreeMap<String, u64>,
+    paths: &BTreeMap<String, (u64, BTreeSet<u32>)>,
+) -> Result<Option<RuntimeStoreUsage>, Error> {
+    let res = read_closure_sizes(&get_version_dir(id, runtime.version)).await;
+    let Some(closure_sizes) = unless_deleted(id, res).await? else {
+        return Ok(None);
+    };
+    let (mut unique_size, mut shared_size) = (0, 0);
+    for (path, size) in runtime_path_sizes {
+        if paths[path].1.len() > 1 {
+            shared_size += size;
+        } else {
+            unique_size += size;
+        }
+    }
+    Ok(Some(RuntimeStoreUsage {
+        id,
+        name: runtime.name.clone(),
+        closure_size: closure_sizes.size,
+        size: unique_size + shared_size,
+        unique_size,
+        shared_size,
+    }))
+}
+
+/// Only the requested runtime is measured, the paths it shares are found among the other runtimes
+/// whose sizes have already been measured. `None` if the runtime doesn't exist
+pub async fn get_runtime_store_usage(
+    metadata: &Metadata,
+    id: u32,
+) -> Result<Option<RuntimeStoreUsage>, Error> {
+    let Some(runtime) = metadata.get(&id) else {
+        return Ok(None);
+    };
+    let runtime_paths = get_runtimes_paths(metadata, |other_id| other_id == id).await?;
+    let Some(runtime_path_sizes) = runtime_paths.get(&id) else {
+        return Ok(None);
+    };
+    get_usage(
+        id,
+        runtime,
+        runtime_path_sizes,
+        &get_path_users(&runtime_paths),
+    )
+    .await
+}
+
+pub async fn get_store_usage(metadata: &Metadata) -> Result<StoreUsage, Error> {
+    let runtime_paths = get_runtimes_paths(metadata, |_| true).await?;
+    let paths = get_path_users(&runtime_paths);
+    let mut runtimes = Vec::new();
+    for (id, runtime_path_sizes) in &runtime_paths {
+        if let Some(usage) = get_usage(*id, &metadata[id], runtime_path_sizes, &paths).await? {
+            runtimes.push(usage);
+        }
+    }
+    runtimes.sort_by_key(|usage| Reverse(usage.unique_size));
+    let runtimes_size = paths.values().map(|(size, _)| size).sum();
+    let mut shared_paths: Vec<SharedPath> = paths
+        .into_iter()
+        .filter(|(_, (_, runtimes))| runtimes.len() > 1)
+        .map(|(path, (size, runtimes))| SharedPath {
+            path,
+            size,
+            runtimes: runtimes.into_iter().collect(),
+        })
+        .collect();
+    shared_paths.sort_by_key(|path| Reverse(path.size));
+    Ok(StoreUsage {
+        store_size: nix::get_store_size().await?,
+        runtimes_size,
+        shared_size: shared_paths.iter().map(|path| path.size).sum(),
+        runtimes,
+        shared_paths,
+    })
+}
@@ -1,26 +1,58 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Error};
 
+use crate::{
+    box_id::BoxId,
+    cleanup::{Cleaner, CleanupTask},
+};
+
 pub struct TempDir {
     pub path: String,
+    box_id: Option<BoxId>,
+    cleaner: Arc<Cleaner>,
+    closed: bool,
 }
 
 impl TempDir {
-    pub async fn new(path: String) -> Result<Self, Error> {
+    pub async fn new(
+        path: String,
+        box_id: Option<BoxId>,
+        cleaner: Arc<Cleaner>,
+    ) -> Result<Self, Error> {
         crate::fs::create_dir_replacing_existing(&path)
             .await
             .map_err(|e| anyhow!("Failed to create directory {path}\nError: {e}"))?;
-        Ok(TempDir { path })
+        Ok(TempDir {
+            path,
+            box_id,
+            cleaner,
+            closed: false,
+        })
+    }
+
+    fn take_cleanup_task(&mut self) -> Option<CleanupTask> {
+        if self.closed {
+            return None;
+        }
+        self.closed = true;
+        Some(CleanupTask::Directory {
+            path: self.path.clone(),
+            box_id_guard: self.box_id.take(),
+        })
+    }
+
+    pub async fn close(mut self) {
+        if let Some(task) = self.take_cleanup_task() {
+            self.cleaner.run(task).await;
+        }
     }
 }
 
 impl Drop for TempDir {
     fn drop(&mut self) {
-        let path = self.path.clone();
-        tokio::spawn(async move {
-            let res = tokio::fs::remove_dir_all(&path).await;
-            if let Err(e) = res {
-                eprintln!("Failed to remove {path}\nError: {e}");
-            }
-        });
+        if let Some(task) = self.take_cleanup_task() {
+            self.cleaner.spawn(task);
+        }
     }
 }
@@ -1,22 +1,24 @@
+use std::sync::Arc;
+
+use anyhow::Error;
 use rusqlite::Connection;
 
-use crate::globals::DB_PATH;
+use crate::cleanup::{Cleaner, CleanupTask, RollbackFn};
 
-pub struct Transaction<T>
-where
-    T: FnOnce(Connection) + Clone + Send + 'static,
-{
-    pub rollback_fn: T,
+pub struct Transaction {
+    rollback_fn: RollbackFn,
+    cleaner: Arc<Cleaner>,
     committed: bool,
 }
 
-impl<T> Transaction<T>
-where
-    T: FnOnce(Connection) + Clone + Send + 'static,
-{
-    pub fn init(rollback_fn: T) -> Transaction<T> {
+impl Transaction {
+    pub fn init<T>(cleaner: Arc<Cleaner>, rollback_fn: T) -> Transaction
+    where
+        T: Fn(&Connection) -> Result<(), Error> + Send + Sync + 'static,
+    {
         Transaction {
-            rollback_fn,
+            rollback_fn: Arc::new(rollback_fn),
+            cleaner,
             committed: false,
         }
     }
@@ -24,33 +26,29 @@ where
     pub fn commit(&mut self) {
         self.committed = true;
     }
+
+    fn take_cleanup_task(&mut self) -> Option<CleanupTask> {
+        if self.committed {
+            return None;
+        }
+        self.committed = true;
+        Some(CleanupTask::Rollback {
+            rollback_fn: self.rollback_fn.clone(),
+        })
+    }
+
+    /// Rolls back the transaction if it was not committed
+    pub async fn close(mut self) {
+        if let Some(task) = self.take_cleanup_task() {
+            self.cleaner.run(task).await;
+        }
+    }
 }
 
-impl<T> Drop for Transaction<T>
-where
-    T: FnOnce(Connection) + Clone + Send + 'static,
-{
+impl Drop for Transaction {
     fn drop(&mut self) {
-        if self.committed {
-            return;
+        if let Some(task) = self.take_cleanup_task() {
+            self.cleaner.spawn(task);
         }
-        let rollback_fn = self.rollback_fn.clone();
-        tokio::spawn(async move {
-            let res = tokio::task::spawn_blocking(move || {
-                let connection = Connection::open(DB_PATH);
-                match connection {
-                    Ok(connection) => {
-                        rollback_fn(connection);
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to open SQLite connection: {e}");
-                    }
-                }
-            })
-            .await;
-            if let Err(e) = res {
-                eprintln!("Failed to spawn async task: {e}");
-            }
-        });
     }
 }
@@ -1,11 +1,18 @@
 use std::collections::HashMap;
 
+use crate::limits::RuntimeLimits;
+
+#[derive(Clone)]
 pub struct Runtime {
     pub name: String,
     pub source_file_name: String,
     pub is_compiled: bool,
+    pub version: u32,
+    pub tags: Vec<String>,
+    pub limits: RuntimeLimits,
 }
 pub type Seconds = f32;
 pub type WholeSeconds = u32;
 pub type Kilobytes = u32;
 pub type Metadata = HashMap<u32, Runtime>;
+pub type Aliases = HashMap<String, u32>;
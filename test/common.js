@@ -22,3 +22,15 @@ module.exports.RUN_MAX_NUMBER_OF_PROCESSES = parseInt(process.env['RUN_MAX_NUMBE
 module.exports.MAX_CONCURRENT_SUBMISSIONS = parseInt(process.env['MAX_CONCURRENT_SUBMISSIONS']);
 
 module.exports.sleep = async (t) => await new Promise((res) => setTimeout(res, t));
+
+module.exports.waitForInstallation = async (id) => {
+  for (;;) {
+    const res = await module.exports.sendRequest(
+      'GET',
+      `${module.exports.BASE_URL}/installations/${id}`
+    );
+    const installation = await res.json();
+    if (installation.status !== 'running') return installation;
+    await module.exports.sleep(100);
+  }
+};
@@ -1,6 +1,7 @@
 const assert = require('assert');
 const {
   sendRequest,
+  waitForInstallation,
   BASE_URL,
   RUN_WALL_TIME,
   RUN_CPU_TIME,
@@ -20,6 +21,9 @@ const {
       source_code: `import os
 print(os.environ["multiline"] == "multi\\nline")
 print(os.environ["spaces"] == "these spaces")
+print(os.environ["equals"] == "a=b=c")
+print(os.environ["assignment_line"] == "first\\nsecond=line")
+print("second" not in os.environ)
 `
     });
 
@@ -27,7 +31,7 @@ print(os.environ["spaces"] == "these spaces")
     console.log(text);
     assert.equal(res.status, 200);
     const body = JSON.parse(text);
-    assert.equal(body.run.stdout, 'True\nTrue\n');
+    assert.equal(body.run.stdout, 'True\nTrue\nTrue\nTrue\nTrue\n');
     assert.equal(body.run.stderr, '');
   }
 
@@ -176,8 +180,11 @@ pkgs.mkShell {
       source_file_name: 'main.sh'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 200);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
   }
 
   // https://github.com/ioi/isolate/issues/158
@@ -717,8 +724,11 @@ pkgs.mkShell {
       source_file_name: 'useless_for_these_tests'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 200);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
   }
 
   {
@@ -742,8 +752,11 @@ pkgs.mkShell {
       source_file_name: 'useless_for_these_tests'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 200);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
   }
 
   {
@@ -803,4 +816,740 @@ pkgs.mkShell {
     const body = JSON.parse(text);
     assert.equal(body.extract.exit_code, 9);
   }
+
+  {
+    console.log('Editing the run script of the Bash runtime');
+    const res = await sendRequest('PATCH', `${BASE_URL}/runtimes/4`, {
+      run_script: 'bash main.sh edited'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.deepEqual(JSON.parse(text), { version: 2 });
+  }
+
+  {
+    console.log('Executing Bash after editing its run script (should keep the same id)');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo $1'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.equal(body.run.stdout, 'edited\n');
+  }
+
+  {
+    console.log('Listing the versions of the Bash runtime');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes/4/versions`);
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    const body = JSON.parse(text);
+    assert.deepEqual(
+      body.map((v) => [v.version, v.is_active]),
+      [
+        [1, false],
+        [2, true]
+      ]
+    );
+  }
+
+  {
+    console.log('Executing the first version of Bash (pinned)');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      runtime_version: 1,
+      source_code: 'echo "[$1]"'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, '[]\n');
+  }
+
+  {
+    console.log('Rolling back Bash to its first version');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes/4/rollback`, { version: 1 });
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo "[$1]"'
+    });
+    const text = await execution_res.text();
+    console.log(text);
+    assert.equal(execution_res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, '[]\n');
+  }
+
+  {
+    console.log('Executing a version that does not exist');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      runtime_version: 100,
+      source_code: 'echo'
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Editing a runtime with an empty run script (should fail)');
+    const res = await sendRequest('PATCH', `${BASE_URL}/runtimes/4`, {
+      run_script: ''
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    const body = JSON.parse(text);
+    assert.equal(body.message, 'Run command can\'t be empty');
+  }
+
+  {
+    console.log('Editing a runtime that does not exist');
+    const res = await sendRequest('PATCH', `${BASE_URL}/runtimes/1000`, {
+      run_script: 'bash main.sh'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Executing Bash by its name');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'Bash',
+      source_code: 'echo hello'
+    });
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'hello\n');
+  }
+
+  {
+    console.log('Creating an alias for Bash and executing through it');
+    const res = await sendRequest('PUT', `${BASE_URL}/aliases/shell`, { runtime_id: 4 });
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'shell',
+      source_code: 'echo hello'
+    });
+    const text = await execution_res.text();
+    console.log(text);
+    assert.equal(execution_res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, 'hello\n');
+
+    const list_res = await sendRequest('GET', `${BASE_URL}/aliases`);
+    assert.deepEqual(await list_res.json(), [{ alias: 'shell', runtime_id: 4 }]);
+  }
+
+  {
+    console.log('Pointing an alias to a runtime that does not exist (should fail)');
+    const res = await sendRequest('PUT', `${BASE_URL}/aliases/shell`, { runtime_id: 1000 });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Installing a runtime named like an alias (should fail)');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'shell',
+      nix_shell: '{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell { }',
+      compile_script: '',
+      run_script: 'bash main.sh',
+      source_file_name: 'main.sh'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    assert.equal(JSON.parse(text).message, 'An alias with this name already exists');
+  }
+
+  {
+    console.log('Renaming a runtime to an alias (should fail)');
+    const res = await sendRequest('PATCH', `${BASE_URL}/runtimes/4`, { name: 'shell' });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 400);
+    assert.equal(JSON.parse(text).message, 'An alias with this name already exists');
+  }
+
+  {
+    console.log('Deleting the alias');
+    const res = await sendRequest('DELETE', `${BASE_URL}/aliases/shell`);
+    assert.equal(res.status, 200);
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime: 'shell',
+      source_code: 'echo hello'
+    });
+    const text = await execution_res.text();
+    console.log(text);
+    assert.equal(execution_res.status, 400);
+    assert.equal(JSON.parse(text).message, 'Runtime with name or alias: shell does not exist');
+  }
+
+  {
+    console.log('Inspecting a runtime');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes/4`);
+    const runtime = await res.json();
+    console.log(runtime);
+    assert.equal(res.status, 200);
+    assert.equal(runtime.name, 'Bash');
+    assert.equal(runtime.is_compiled, false);
+    assert.equal(runtime.compile_script, null);
+    assert.ok(runtime.nix_shell.length > 0);
+    assert.ok(runtime.run_script.length > 0);
+    assert.ok('PATH' in runtime.env);
+    assert.ok(runtime.usage.total_executions > 0);
+
+    const redacted_res = await sendRequest('GET', `${BASE_URL}/runtimes/4?redact_env=true`);
+    const redacted = await redacted_res.json();
+    assert.equal(redacted.env.PATH, null);
+  }
+
+  {
+    console.log('Inspecting a runtime that does not exist (should fail)');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes/1000`);
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Tagging a runtime and filtering the runtimes by tag');
+    const res = await sendRequest('PUT', `${BASE_URL}/runtimes/4/tags`, { tags: ['shell', 'interpreted'] });
+    console.log(await res.text());
+    assert.equal(res.status, 200);
+
+    const list_res = await sendRequest('GET', `${BASE_URL}/runtimes?tag=shell&details=true`);
+    const runtimes = await list_res.json();
+    console.log(runtimes);
+    assert.equal(runtimes.length, 1);
+    assert.equal(runtimes[0].id, 4);
+    assert.deepEqual(runtimes[0].tags, ['interpreted', 'shell']);
+    assert.equal(runtimes[0].is_compiled, false);
+  }
+
+  {
+    console.log('Listing runtimes with filters and pagination');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes?compiled=false&name_prefix=Ba`);
+    const runtimes = await res.json();
+    assert.deepEqual(runtimes, [{ id: 4, name: 'Bash' }]);
+
+    const all_res = await sendRequest('GET', `${BASE_URL}/runtimes`);
+    const all = await all_res.json();
+    const ids = all.map((runtime) => runtime.id);
+    assert.deepEqual(
+      ids,
+      [...ids].sort((a, b) => a - b)
+    );
+
+    const page_res = await sendRequest('GET', `${BASE_URL}/runtimes?offset=1&limit=1`);
+    assert.deepEqual(await page_res.json(), all.slice(1, 2));
+    assert.equal(page_res.headers.get('x-total-count'), `${all.length}`);
+  }
+
+  {
+    console.log('Listing runtimes with a matching ETag (should not be modified)');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes`);
+    const etag = res.headers.get('etag');
+    assert.ok(etag);
+
+    const cached_res = await fetch(`${BASE_URL}/runtimes`, { headers: { 'If-None-Match': etag } });
+    assert.equal(cached_res.status, 304);
+    assert.equal(cached_res.headers.get('x-total-count'), res.headers.get('x-total-count'));
+  }
+
+  {
+    console.log(
+      'Listing the same page of runtimes with a different total count (should be modified)'
+    );
+    const page_res = await sendRequest('GET', `${BASE_URL}/runtimes?limit=1`);
+    const [first] = await page_res.json();
+    const filtered_res = await fetch(
+      `${BASE_URL}/runtimes?limit=1&name_prefix=${encodeURIComponent(first.name)}`,
+      { headers: { 'If-None-Match': page_res.headers.get('etag') } }
+    );
+    assert.notEqual(
+      filtered_res.headers.get('x-total-count'),
+      page_res.headers.get('x-total-count')
+    );
+    assert.equal(filtered_res.status, 200);
+    assert.deepEqual(await filtered_res.json(), [first]);
+  }
+
+  {
+    console.log('Exporting a runtime');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes/4/export`);
+    const bundle = await res.json();
+    console.log(bundle);
+    assert.equal(res.status, 200);
+    assert.equal(bundle.format_version, 1);
+    assert.equal(bundle.runtimes.length, 1);
+    assert.equal(bundle.runtimes[0].name, 'Bash');
+    assert.equal(bundle.runtimes[0].source_file_name, 'main.sh');
+    assert.deepEqual(bundle.runtimes[0].tags, ['interpreted', 'shell']);
+
+    const all_res = await sendRequest('GET', `${BASE_URL}/runtimes/export`);
+    const all = await all_res.json();
+    assert.ok(all.runtimes.some((runtime) => runtime.name === 'Bash'));
+  }
+
+  {
+    console.log('Importing a bundle with an unsupported format version (should fail)');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes/import`, {
+      format_version: 1000,
+      runtimes: []
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  let imported_id;
+  {
+    console.log('Importing an exported runtime under another name with its own limits');
+    const export_res = await sendRequest('GET', `${BASE_URL}/runtimes/4/export`);
+    const bundle = await export_res.json();
+    bundle.runtimes[0].name = 'Imported Bash';
+    bundle.runtimes[0].limits = { compile: null, run: { wall_time: RUN_WALL_TIME / 2 } };
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes/import`, bundle);
+    const imported = await res.json();
+    console.log(imported);
+    assert.equal(res.status, 202);
+    assert.equal(imported.length, 1);
+    assert.equal(imported[0].name, 'Imported Bash');
+
+    const installation = await waitForInstallation(imported[0].installation_id);
+    assert.equal(installation.status, 'succeeded');
+    imported_id = installation.runtime_id;
+
+    const runtime_res = await sendRequest('GET', `${BASE_URL}/runtimes/${imported_id}`);
+    const runtime = await runtime_res.json();
+    assert.ok(Math.abs(runtime.limits.run.wall_time - RUN_WALL_TIME / 2) < 0.001);
+    assert.equal(runtime.limits.run.memory, RUN_MEMORY);
+    assert.deepEqual(runtime.tags, ['interpreted', 'shell']);
+  }
+
+  {
+    console.log('Executing the imported runtime (should use its own limits)');
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: imported_id,
+      source_code: 'echo $1'
+    });
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 200);
+    assert.equal(JSON.parse(text).run.stdout, '[]\n');
+  }
+
+  {
+    console.log('Installing a runtime with limits above the system limits (should fail)');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Greedy Bash',
+      nix_shell: 'not evaluated',
+      compile_script: '',
+      run_script: 'bash $1',
+      source_file_name: 'main.sh',
+      limits: { run: { memory: RUN_MEMORY + 1 } }
+    });
+    console.log(await res.text());
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Planning a manifests sync without MANIFESTS_DIR (should fail)');
+    const res = await sendRequest('GET', `${BASE_URL}/manifests/plan`);
+    console.log(await res.text());
+    assert.equal(res.status, 404);
+  }
+
+  {
+    console.log('Installing a runtime with a failing smoke test (should fail)');
+    const export_res = await sendRequest('GET', `${BASE_URL}/runtimes/4/export`);
+    const { tags, ...definition } = (await export_res.json()).runtimes[0];
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      ...definition,
+      name: 'Broken Bash',
+      tests: [{ source_code: 'echo hello', expected_output: 'goodbye' }]
+    });
+    const { id } = await res.json();
+    assert.equal(res.status, 202);
+
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'failed');
+
+    const list_res = await sendRequest('GET', `${BASE_URL}/runtimes?name_prefix=Broken`);
+    assert.deepEqual(await list_res.json(), []);
+  }
+
+  let tested_id;
+  {
+    console.log('Installing a runtime with passing smoke tests');
+    const export_res = await sendRequest('GET', `${BASE_URL}/runtimes/4/export`);
+    const { tags, ...definition } = (await export_res.json()).runtimes[0];
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      ...definition,
+      name: 'Tested Bash',
+      tests: [
+        { source_code: 'echo hello', expected_output: 'hello' },
+        { source_code: 'cat', input: 'from stdin', expected_output: 'from stdin' }
+      ]
+    });
+    const { id } = await res.json();
+    assert.equal(res.status, 202);
+
+    const installation = await waitForInstallation(id);
+    assert.equal(installation.status, 'succeeded');
+    tested_id = installation.runtime_id;
+  }
+
+  {
+    console.log('Running the smoke tests of an installed runtime');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes/${tested_id}/test`);
+    const report = await res.json();
+    console.log(report);
+    assert.equal(res.status, 200);
+    assert.equal(report.passed, true);
+    assert.equal(report.tests.length, 2);
+  }
+
+  {
+    console.log('Editing a runtime so that its smoke tests fail (should fail)');
+    const res = await sendRequest('PATCH', `${BASE_URL}/runtimes/${tested_id}`, {
+      run_script: 'echo broken'
+    });
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 400);
+    assert.equal(body.tests.passed, false);
+
+    const runtime_res = await sendRequest('GET', `${BASE_URL}/runtimes/${tested_id}`);
+    assert.equal((await runtime_res.json()).version, 1);
+  }
+
+  {
+    console.log('Installing a runtime with both a nix shell and a flake (should fail)');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Ambiguous Bash',
+      nix_shell: 'not evaluated',
+      flake: 'github:NixOS/nixpkgs#bash',
+      compile_script: '',
+      run_script: 'bash $1',
+      source_file_name: 'main.sh'
+    });
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 400);
+    assert.equal(body.message, 'Only one of nix_shell and flake can be specified');
+  }
+
+  {
+    console.log('Installing a runtime with an invalid nixpkgs revision (should fail)');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Unpinned Bash',
+      nix_shell: '{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell {}',
+      nixpkgs: { revision: 'master"; evil = "', sha256: 'abc' },
+      compile_script: '',
+      run_script: 'bash $1',
+      source_file_name: 'main.sh'
+    });
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 400);
+    assert.equal(body.message, 'Invalid nixpkgs revision, expected a full commit hash');
+  }
+
+  {
+    console.log('Installing a runtime with a pinned nixpkgs revision');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Pinned Bash',
+      nix_shell: `
+{ pkgs ? import <nixpkgs> {} }:
+pkgs.mkShell {
+  nativeBuildInputs = with pkgs; [
+    bash
+  ];
+}`,
+      nixpkgs: {
+        revision: '72da83d9515b43550436891f538ff41d68eecc7f',
+        sha256: '177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y'
+      },
+      compile_script: '',
+      run_script: 'bash $1',
+      source_file_name: 'main.sh'
+    });
+    const { id } = await res.json();
+    assert.equal(res.status, 202);
+
+    const installation = await waitForInstallation(id);
+    assert.equal(installation.status, 'succeeded');
+
+    const runtime_res = await sendRequest('GET', `${BASE_URL}/runtimes/${installation.runtime_id}`);
+    const runtime = await runtime_res.json();
+    console.log(runtime.lock);
+    assert.equal(runtime.nixpkgs.revision, '72da83d9515b43550436891f538ff41d68eecc7f');
+    assert.equal(runtime.lock.nixpkgs.revision, '72da83d9515b43550436891f538ff41d68eecc7f');
+    assert.ok(runtime.lock.nixpkgs.path.startsWith('/nix/store/'));
+    assert.ok(runtime.lock.store_paths.length > 0);
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: installation.runtime_id,
+      source_code: 'echo pinned'
+    });
+    assert.equal((await execution_res.json()).run.stdout, 'pinned\n');
+  }
+
+  {
+    console.log('Importing an invalid closure archive (should fail)');
+    const res = await fetch(`${BASE_URL}/runtimes/import/closure`, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/x-tar' },
+      body: 'not a tar archive'
+    });
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 400);
+    assert.ok(body.message.startsWith('Invalid closure archive'));
+  }
+
+  {
+    console.log('Exporting the closure of a runtime and importing it after deleting the runtime');
+    const export_res = await sendRequest('GET', `${BASE_URL}/runtimes/${tested_id}/export/closure`);
+    assert.equal(export_res.status, 200);
+    assert.equal(export_res.headers.get('content-type'), 'application/x-tar');
+    const archive = await export_res.arrayBuffer();
+
+    const conflict_res = await fetch(`${BASE_URL}/runtimes/import/closure`, {
+      method: 'POST',
+      body: archive
+    });
+    console.log(await conflict_res.text());
+    assert.equal(conflict_res.status, 400);
+
+    // Collecting garbage removes the store paths that only the deleted runtime used
+    const delete_res = await sendRequest(
+      'DELETE',
+      `${BASE_URL}/runtimes/${tested_id}?collect_garbage=true`
+    );
+    assert.equal(delete_res.status, 200);
+
+    const res = await fetch(`${BASE_URL}/runtimes/import/closure`, {
+      method: 'POST',
+      body: archive
+    });
+    const { id } = await res.json();
+    assert.equal(res.status, 202);
+
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: installation.runtime_id,
+      source_code: 'echo offline'
+    });
+    assert.equal((await execution_res.json()).run.stdout, 'offline\n');
+  }
+
+  {
+    console.log('Rebuilding a runtime');
+    const before_res = await sendRequest('GET', `${BASE_URL}/runtimes/4`);
+    const before = await before_res.json();
+
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes/4/rebuild`);
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 200);
+    assert.ok(body.version > before.version);
+
+    const after_res = await sendRequest('GET', `${BASE_URL}/runtimes/4`);
+    const after = await after_res.json();
+    assert.equal(after.version, body.version);
+    assert.equal(after.nix_shell, before.nix_shell);
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 4,
+      source_code: 'echo rebuilt'
+    });
+    assert.equal((await execution_res.json()).run.stdout, 'rebuilt\n');
+  }
+
+  {
+    console.log('Rebuilding all runtimes in the background');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes/rebuild`);
+    const { id } = await res.json();
+    assert.equal(res.status, 202);
+
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.runtime_id, null);
+    const logs_res = await sendRequest('GET', `${BASE_URL}/installations/${id}/logs`);
+    const logs = await logs_res.text();
+    console.log(logs);
+    assert.ok(logs.includes('Rebuilding runtime'));
+    assert.ok(logs.includes('with id: 4'));
+  }
+
+  {
+    console.log('Getting the Nix status');
+    const res = await sendRequest('GET', `${BASE_URL}/update`);
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 200);
+    assert.ok(body.version);
+    assert.ok(body.generations.some((generation) => generation.current));
+  }
+
+  {
+    console.log('Updating Nix from an invalid channel');
+    const res = await sendRequest('POST', `${BASE_URL}/update`, {
+      channel: 'nixpkgs-unstable; rm -rf /'
+    });
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Updating Nix from both a channel and a revision');
+    const res = await sendRequest('POST', `${BASE_URL}/update`, {
+      channel: 'nixpkgs-unstable',
+      revision: '72da83d9515b43550436891f538ff41d68eecc7f'
+    });
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log(
+      'Updating Nix with a malformed request (should not fall back to the default channel)'
+    );
+    const res = await sendRequest('POST', `${BASE_URL}/update`, {
+      revision: 123
+    });
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Installing a runtime with environment settings');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Bash (filtered env)',
+      nix_shell: `
+{ pkgs ? import (
+  fetchTarball {
+    url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+    sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+  }
+) {} }:
+pkgs.mkShell {
+  shellHook = ''
+export KEPT_ONE="kept"
+export DROPPED_ONE="dropped"
+  '';
+}`,
+      compile_script: '',
+      run_script: 'bash main.sh',
+      source_file_name: 'main.sh',
+      env_settings: {
+        allow: ['PATH', 'KEPT_*', 'DROPPED_*'],
+        deny: ['DROPPED_*'],
+        extra: { GREETING: 'hello=world' }
+      }
+    });
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
+
+    const details_res = await sendRequest('GET', `${BASE_URL}/runtimes/${installation.runtime_id}`);
+    const details = await details_res.json();
+    assert.deepEqual(Object.keys(details.env).sort(), ['GREETING', 'KEPT_ONE', 'PATH']);
+    assert.deepEqual(details.env_settings.deny, ['DROPPED_*']);
+
+    const execution_res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: installation.runtime_id,
+      source_code: 'echo "$KEPT_ONE $DROPPED_ONE $GREETING"'
+    });
+    assert.equal((await execution_res.json()).run.stdout, 'kept  hello=world\n');
+  }
+
+  {
+    console.log('Checking that build-time variables are dropped by default');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes/2`);
+    const body = await res.json();
+    assert.equal(res.status, 200);
+    assert.ok(!('NIX_BUILD_TOP' in body.env));
+    assert.ok(!('buildPhase' in body.env));
+    assert.ok('multiline' in body.env);
+  }
+
+  {
+    console.log('Installing a runtime with an invalid extra environment variable');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Bash (bad env)',
+      nix_shell: '{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell {}',
+      compile_script: '',
+      run_script: 'bash main.sh',
+      source_file_name: 'main.sh',
+      env_settings: { extra: { 'BAD NAME': 'value' } }
+    });
+    assert.equal(res.status, 400);
+  }
+
+  {
+    console.log('Checking that only the closure of the runtime is mounted');
+    const details_res = await sendRequest('GET', `${BASE_URL}/runtimes/3`);
+    const other_path = (await details_res.json()).env.PATH.split(':')
+      .find((dir) => dir.startsWith('/nix/store/') && dir.includes('gcc'));
+    assert.ok(other_path);
+
+    const res = await sendRequest('POST', `${BASE_URL}/execute`, {
+      runtime_id: 2,
+      source_code: `import os
+print(os.path.exists("/nix/var"))
+print(os.path.exists("${other_path}"))
+print(any("python3" in entry for entry in os.listdir("/nix/store")))
+`
+    });
+    const body = await res.json();
+    console.log(body);
+    assert.equal(res.status, 200);
+    assert.equal(body.run.stdout, 'False\nFalse\nTrue\n');
+  }
+
+  {
+    console.log('Getting the store usage of a runtime');
+    const res = await sendRequest('GET', `${BASE_URL}/runtimes/2`);
+    const body = await res.json();
+    console.log(body.store_usage);
+    assert.equal(res.status, 200);
+    assert.ok(body.store_usage.closure_size > 0);
+    assert.equal(
+      body.store_usage.size,
+      body.store_usage.unique_size + body.store_usage.shared_size
+    );
+  }
+
+  {
+    console.log('Getting the store usage summary');
+    const res = await sendRequest('GET', `${BASE_URL}/store/usage`);
+    const body = await res.json();
+    assert.equal(res.status, 200);
+    assert.ok(body.store_size >= body.runtimes_size);
+    assert.ok(body.runtimes.some((runtime) => runtime.id === 2));
+    // Python and C++ share at least glibc
+    assert.ok(body.shared_paths.some((path) => path.runtimes.includes(2) && path.runtimes.includes(3)));
+  }
 })();
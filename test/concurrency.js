@@ -1,5 +1,10 @@
 const assert = require('assert');
-const { sendRequest, BASE_URL, sleep, MAX_CONCURRENT_SUBMISSIONS } = require('./common');
+const {
+  sendRequest,
+  waitForInstallation,
+  BASE_URL,
+  MAX_CONCURRENT_SUBMISSIONS
+} = require('./common');
 
 (async () => {
   {
@@ -105,13 +110,12 @@ int main() {
 
   {
     console.log(
-      'Executing Math.ceil(MAX_CONCURRENT_SUBMISSIONS / 2) submissions after a package installation has started (they should start after the installation)'
+      'Executing Math.ceil(MAX_CONCURRENT_SUBMISSIONS / 2) submissions while a package installation is running (they should not wait for the installation)'
     );
 
-    const installation_promise = (async () => {
-      await sendRequest('POST', `${BASE_URL}/runtimes`, {
-        name: 'Fake lang',
-        nix_shell: `{ pkgs ? import (
+    const installation_res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Fake lang',
+      nix_shell: `{ pkgs ? import (
     fetchTarball {
       url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
       sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
@@ -119,110 +123,48 @@ int main() {
   ) {} }:
   pkgs.mkShell {
     shellHook = ''
-    sleep 0.3
+    sleep 3
     exit 1
     '';
     nativeBuildInputs = with pkgs; [];
   }`,
-        compile_script: 'g++ main.cpp',
-        run_script: './a.out',
-        source_file_name: 'main.cpp'
-      });
-      return new Date();
-    })();
-
-    await sleep(10);
+      compile_script: 'g++ main.cpp',
+      run_script: './a.out',
+      source_file_name: 'main.cpp'
+    });
+    assert.equal(installation_res.status, 202);
+    const { id } = await installation_res.json();
 
     const promises = [];
     for (let i = 0; i < Math.ceil(MAX_CONCURRENT_SUBMISSIONS / 2); ++i) {
       promises.push(
-        (async () => {
-          await sendRequest('POST', `${BASE_URL}/execute`, {
-            runtime_id: 2,
-            source_code: 'print("Hello world")'
-          });
-          return new Date();
-        })()
+        sendRequest('POST', `${BASE_URL}/execute`, {
+          runtime_id: 2,
+          source_code: 'print("Hello world")'
+        })
       );
     }
-
-    const installation_finish = await installation_promise;
-    const before = new Date();
-    const execution_finishes = await Promise.all(promises);
-    const total_time = new Date() - before;
-    for (const finish of execution_finishes) {
-      assert.ok(
-        finish >= installation_finish,
-        'Found a submission that finished before the installation'
-      );
+    for (const res of await Promise.all(promises)) {
+      assert.equal(res.status, 200);
     }
-    console.log(`Approximate time to run all submissions: ${total_time} ms`);
-  }
 
-  {
-    console.log(
-      'Running a package installation after executing Math.ceil(MAX_CONCURRENT_SUBMISSIONS / 2) submissions has started (it should start after the executions finish)'
-    );
-
-    const execution_promises = [];
-    for (let i = 0; i < Math.ceil(MAX_CONCURRENT_SUBMISSIONS / 2); ++i) {
-      execution_promises.push(
-        (async () => {
-          await sendRequest('POST', `${BASE_URL}/execute`, {
-            runtime_id: 2,
-            source_code: `
-  import time
-  time.sleep(0.3)`
-          });
-          return new Date();
-        })()
-      );
-    }
-    await sleep(10);
-
-    const installation_promise = (async () => {
-      await sendRequest('POST', `${BASE_URL}/runtimes`, {
-        name: 'Fake lang',
-        nix_shell: `{ pkgs ? import (
-    fetchTarball {
-      url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
-      sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
-    }
-  ) {} }:
-  pkgs.mkShell {
-    shellHook = ''
-    exit 1
-    '';
-    nativeBuildInputs = with pkgs; [];
-  }`,
-        compile_script: 'g++ main.cpp',
-        run_script: './a.out',
-        source_file_name: 'main.cpp'
-      });
-      return new Date();
-    })();
-
-    const execution_finishes = await Promise.all(execution_promises);
-    const last_execution_finish = Math.max(...execution_finishes);
-    const before = new Date();
-    const installation_finish = await installation_promise;
-    const duration = new Date() - before;
-
-    console.log(`Time to finish installation: ${duration} ms`);
-    assert.ok(
-      installation_finish > last_execution_finish,
-      'Installation finished before last execution'
+    const res = await sendRequest('GET', `${BASE_URL}/installations/${id}`);
+    const installation = await res.json();
+    assert.equal(
+      installation.status,
+      'running',
+      'The installation finished before the submissions'
     );
+    assert.equal((await waitForInstallation(id)).status, 'failed');
   }
 
   {
     console.log(
-      'Running a package installation after another installation has started (it should start after the installation finishes)'
+      'Installing a runtime while another installation with the same name is running (should conflict)'
     );
-    const first_promise = (async () => {
-      await sendRequest('POST', `${BASE_URL}/runtimes`, {
-        name: 'Fake lang',
-        nix_shell: `{ pkgs ? import (
+    const first_res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Fake lang',
+      nix_shell: `{ pkgs ? import (
     fetchTarball {
       url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
       sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
@@ -230,24 +172,21 @@ int main() {
   ) {} }:
   pkgs.mkShell {
     shellHook = ''
-    sleep 0.3
+    sleep 1
     exit 1
     '';
     nativeBuildInputs = with pkgs; [];
   }`,
-        compile_script: 'g++ main.cpp',
-        run_script: './a.out',
-        source_file_name: 'main.cpp'
-      });
-      return new Date();
-    })();
-
-    await sleep(10);
+      compile_script: 'g++ main.cpp',
+      run_script: './a.out',
+      source_file_name: 'main.cpp'
+    });
+    assert.equal(first_res.status, 202);
+    const { id } = await first_res.json();
 
-    const second_promise = (async () => {
-      await sendRequest('POST', `${BASE_URL}/runtimes`, {
-        name: 'Fake lang',
-        nix_shell: `{ pkgs ? import (
+    const second_res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Fake lang',
+      nix_shell: `{ pkgs ? import (
     fetchTarball {
       url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
       sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
@@ -255,33 +194,29 @@ int main() {
   ) {} }:
   pkgs.mkShell {
     shellHook = ''
+    sleep 0
     exit 1
     '';
     nativeBuildInputs = with pkgs; [];
   }`,
-        compile_script: 'g++ main.cpp',
-        run_script: './a.out',
-        source_file_name: 'main.cpp'
-      });
-      return new Date();
-    })();
-
-    const first_finish = await first_promise;
-    const before = new Date();
-    const second_finish = await second_promise;
-    const duration = new Date() - before;
-    console.log(`Time to finish second installation: ${duration} ms`);
-    assert.ok(
-      second_finish >= first_finish,
-      'Second installation finished before first installation'
-    );
+      compile_script: 'g++ main.cpp',
+      run_script: './a.out',
+      source_file_name: 'main.cpp'
+    });
+    const text = await second_res.text();
+    console.log(text);
+    assert.equal(second_res.status, 409);
+    assert.deepEqual(JSON.parse(text), {
+      message: 'A runtime with this name is already being installed'
+    });
+    assert.equal((await waitForInstallation(id)).status, 'failed');
   }
 
   {
     console.log(
       'Getting the available runtimes while an installation is running (should not be blocked)'
     );
-    const installation_promise = sendRequest('POST', `${BASE_URL}/runtimes`, {
+    const installation_res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
       name: 'Fake lang',
       nix_shell: `{ pkgs ? import (
     fetchTarball {
@@ -300,8 +235,8 @@ int main() {
       run_script: './a.out',
       source_file_name: 'main.cpp'
     });
-
-    await sleep(10);
+    assert.equal(installation_res.status, 202);
+    const { id } = await installation_res.json();
 
     const before = new Date();
     const res = await sendRequest('GET', `${BASE_URL}/runtimes`);
@@ -309,6 +244,6 @@ int main() {
     console.log(`Time taken: ${duration} ms`);
     assert.equal(res.status, 200);
     assert.ok(duration < 200);
-    await installation_promise;
+    await waitForInstallation(id);
   }
 })();
@@ -1,5 +1,5 @@
 const assert = require('assert');
-const { sendRequest, BASE_URL } = require('./common');
+const { sendRequest, waitForInstallation, BASE_URL } = require('./common');
 
 (async () => {
   {
@@ -23,8 +23,11 @@ pkgs.mkShell {
       source_file_name: 'main.py'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 200);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
   }
 
   {
@@ -122,6 +125,9 @@ pkgs.mkShell {
 export multiline="multi
 line"
 export spaces="these spaces"
+export equals="a=b=c"
+export assignment_line="first
+second=line"
   '';
   nativeBuildInputs = with pkgs; [
       python3
@@ -132,8 +138,11 @@ export spaces="these spaces"
       source_file_name: 'main.py'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 200);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
   }
 
   {
@@ -157,8 +166,11 @@ pkgs.mkShell {
       source_file_name: 'main.cpp'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 200);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'succeeded');
   }
 
   {
@@ -184,7 +196,65 @@ pkgs.mkShell {
       source_file_name: 'main.cpp'
     });
 
-    console.log(await res.text());
-    assert.equal(res.status, 400);
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+    const installation = await waitForInstallation(id);
+    console.log(installation);
+    assert.equal(installation.status, 'failed');
+
+    console.log('Getting the logs of the failed installation');
+    const logs_res = await sendRequest('GET', `${BASE_URL}/installations/${id}/logs`);
+    const logs = await logs_res.text();
+    console.log(logs);
+    assert.equal(logs_res.status, 200);
+    assert.ok(logs.includes('Installation failed'));
+  }
+
+  {
+    console.log('Getting an installation that does not exist');
+    const res = await sendRequest('GET', `${BASE_URL}/installations/1000`);
+
+    const text = await res.text();
+    console.log(text);
+    assert.equal(res.status, 404);
+    let body = JSON.parse(text);
+    assert.deepEqual(body, { message: 'Could not find the specified installation' });
+  }
+
+  {
+    console.log('Cancelling an installation that is running');
+    const res = await sendRequest('POST', `${BASE_URL}/runtimes`, {
+      name: 'Slow lang',
+      nix_shell: `
+{ pkgs ? import (
+  fetchTarball {
+    url="https://github.com/NixOS/nixpkgs/archive/72da83d9515b43550436891f538ff41d68eecc7f.tar.gz";
+    sha256="177sws22nqkvv8am76qmy9knham2adfh3gv7hrjf6492z1mvy02y";
+  }
+) {} }:
+pkgs.mkShell {
+  shellHook = ''
+  sleep 30
+  '';
+  nativeBuildInputs = with pkgs; [];
+}
+`,
+      compile_script: '',
+      run_script: './a.out',
+      source_file_name: 'main.cpp'
+    });
+    assert.equal(res.status, 202);
+    const { id } = await res.json();
+
+    const cancel_res = await sendRequest('DELETE', `${BASE_URL}/installations/${id}`);
+    const text = await cancel_res.text();
+    console.log(text);
+    assert.equal(cancel_res.status, 200);
+    assert.equal(JSON.parse(text).status, 'cancelled');
+
+    console.log('Cancelling the same installation again (should fail)');
+    const second_cancel_res = await sendRequest('DELETE', `${BASE_URL}/installations/${id}`);
+    console.log(await second_cancel_res.text());
+    assert.equal(second_cancel_res.status, 409);
   }
 })();